@@ -0,0 +1,121 @@
+use serde::{Deserialize, Serialize};
+use ::std::error::Error;
+use ::std::fs;
+use ::std::path::Path;
+use crate::rotation::TSpin;
+use crate::score::ScoreKind;
+
+
+// Garbage lines sent to an opponent for each way of clearing; tables by
+// lines cleared (or by combo) hold their last entry for anything past it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AttackTable {
+    // by lines cleared at once, without a T-spin
+    pub clears: Vec<u32>,
+    // by lines cleared with a mini T-spin
+    pub mini_t_spins: Vec<u32>,
+    // by lines cleared with a T-spin
+    pub t_spins: Vec<u32>,
+    // extra lines for a clear that's back-to-back
+    pub back_to_back: u32,
+    // extra lines for each clear in a row, from the second one onwards
+    pub combos: Vec<u32>,
+    pub perfect_clear: u32,
+}
+
+impl AttackTable {
+    pub fn load(path: &Path) -> Result<Self, Box<dyn Error>> {
+        Ok(ron::from_str(&fs::read_to_string(path)?)?)
+    }
+
+    // Garbage lines that the given way of scoring sends to an opponent
+    pub fn attack(&self, kind: ScoreKind) -> u32 {
+        match kind {
+            ScoreKind::SoftDrop { .. } | ScoreKind::HardDrop { .. } => 0,
+            ScoreKind::Clear { lines: 0, .. } => 0,
+            ScoreKind::Clear { lines, t_spin, back_to_back } => {
+                let table = match t_spin {
+                    None => &self.clears,
+                    Some(TSpin::Mini) => &self.mini_t_spins,
+                    Some(TSpin::Full) => &self.t_spins,
+                };
+                let bonus = if back_to_back { self.back_to_back } else { 0 };
+                lookup(table, lines as usize) + bonus
+            },
+            ScoreKind::Combo(combo) => {
+                lookup(&self.combos, combo.saturating_sub(1) as usize)
+            },
+            ScoreKind::PerfectClear { .. } => self.perfect_clear,
+        }
+    }
+}
+
+// as per the guideline
+impl Default for AttackTable {
+    fn default() -> Self {
+        Self {
+            clears: vec![0, 0, 1, 2, 4],
+            mini_t_spins: vec![0, 0, 1],
+            t_spins: vec![0, 2, 4, 6],
+            back_to_back: 1,
+            combos: vec![1, 1, 2, 2, 3, 3, 4, 4, 4, 5],
+            perfect_clear: 10,
+        }
+    }
+}
+
+fn lookup(table: &[u32], idx: usize) -> u32 {
+    table.get(idx).or(table.last()).copied().unwrap_or_default()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+
+    fn clear(
+        lines: u8,
+        t_spin: Option<TSpin>,
+        back_to_back: bool,
+    ) -> ScoreKind {
+        ScoreKind::Clear { lines, t_spin, back_to_back }
+    }
+
+    #[test]
+    fn clears_attack_by_lines_and_t_spin() {
+        let table = AttackTable::default();
+        assert_eq!(table.attack(clear(1, None, false)), 0);
+        assert_eq!(table.attack(clear(4, None, false)), 4);
+        assert_eq!(table.attack(clear(2, Some(TSpin::Mini), false)), 1);
+        assert_eq!(table.attack(clear(2, Some(TSpin::Full), false)), 4);
+        assert_eq!(table.attack(clear(0, Some(TSpin::Full), false)), 0);
+        assert_eq!(table.attack(ScoreKind::HardDrop { rows: 20 }), 0);
+    }
+
+    #[test]
+    fn back_to_back_and_combos_add_to_the_attack() {
+        let table = AttackTable::default();
+        assert_eq!(table.attack(clear(4, None, true)), 5);
+        assert_eq!(table.attack(clear(3, Some(TSpin::Full), true)), 7);
+        assert_eq!(table.attack(ScoreKind::Combo(1)), 1);
+        assert_eq!(table.attack(ScoreKind::Combo(3)), 2);
+        let perfect_clear = ScoreKind::PerfectClear {
+            lines: 4,
+            back_to_back: false,
+        };
+        assert_eq!(table.attack(perfect_clear), 10);
+    }
+
+    #[test]
+    fn tables_hold_their_last_entry() {
+        let table = AttackTable {
+            clears: vec![0, 1],
+            combos: Vec::new(),
+            ..AttackTable::default()
+        };
+        assert_eq!(table.attack(clear(4, None, false)), 1);
+        assert_eq!(table.attack(ScoreKind::Combo(20)), 0);
+        assert_eq!(AttackTable::default().attack(ScoreKind::Combo(50)), 5);
+    }
+}
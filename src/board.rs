@@ -0,0 +1,28 @@
+use bevy::prelude::*;
+use crate::grid::GridSize;
+use crate::BLOCK_SIZE;
+
+
+// columns between boards side by side, leaving room for each one's HUD
+const BOARD_GAP: f32 = 8.0;
+
+// One player's field; everything about their game in progress (the size of
+// the grid, the heap, the piece, its timers and so on) lives on the board as
+// components, and the blocks on the grid are the board's children
+#[derive(Component)]
+pub struct Board {
+    // counting from 0, in the order the boards are laid out
+    pub player: usize,
+}
+
+
+// Where the center of a player's board goes, given how many there are
+pub fn board_position(
+    player: usize,
+    players: usize,
+    grid_size: GridSize,
+) -> Transform {
+    let spacing = (grid_size.width as f32 + BOARD_GAP) * BLOCK_SIZE;
+    let offset = player as f32 - (players as f32 - 1.0) * 0.5;
+    Transform::from_xyz(offset * spacing, 0.0, 0.0)
+}
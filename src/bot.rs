@@ -0,0 +1,541 @@
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+use serde::{Deserialize, Serialize};
+use ::std::collections::VecDeque;
+use ::std::error::Error;
+use ::std::fs;
+use ::std::path::Path;
+use crate::board::Board;
+use crate::grid::{GridPos, GridSize};
+use crate::heap::{Heap, HeapEntry};
+use crate::input::{Action, InputQueue};
+use crate::movement::{MoveOffset, MoveX, MoveY, can_move};
+use crate::net::{INPUT_DELAY, NetSession};
+use crate::piece::{
+    ActivePiece,
+    Block,
+    Hold,
+    Origin,
+    PieceKind,
+    PieceQueue,
+    Randomizer,
+    coming,
+};
+use crate::placement::{
+    Motion,
+    Placement,
+    Step,
+    placements,
+    placements_from,
+};
+use crate::replay::Playback;
+use crate::rotation::{Rotate, TSpin};
+use crate::stats::Stats;
+use crate::tick::GameClock;
+
+
+// placements that look best right away, which are the only ones looked
+// further ahead from
+const BEAM_WIDTH: usize = 4;
+
+// most pieces a bot may think ahead to; every piece more multiplies the
+// searches it makes, all in the one tick
+pub const MAX_LOOKAHEAD: usize = 1;
+
+// what a placement that leaves blocks above the visible field is worth,
+// whatever else comes of it
+const TOPPED_OUT: f32 = -1.0e9;
+
+// How much each feature of a heap counts towards how good a placement is;
+// features that are bad to have get negative weights
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BotWeights {
+    // empty cells with a block somewhere above them
+    pub holes: f32,
+    // differences in height between neighboring columns
+    pub bumpiness: f32,
+    // heights of all columns added up
+    pub height: f32,
+    // how far columns are below both of their neighbors
+    pub wells: f32,
+    // by lines cleared at once (holding the last entry for anything past
+    // it), without a T-spin
+    pub clears: Vec<f32>,
+    // by lines cleared with a T-spin
+    pub t_spins: Vec<f32>,
+    // slots that a T could be spun into
+    pub t_slots: f32,
+}
+
+impl BotWeights {
+    pub fn load(path: &Path) -> Result<Self, Box<dyn Error>> {
+        Ok(ron::from_str(&fs::read_to_string(path)?)?)
+    }
+
+    // Worth of what a placement cleared
+    fn clear(&self, lines: usize, t_spin: Option<TSpin>) -> f32 {
+        let table = match t_spin {
+            Some(TSpin::Full) => &self.t_spins,
+            _ => &self.clears,
+        };
+        table
+            .get(lines)
+            .or(table.last())
+            .copied()
+            .unwrap_or_default()
+    }
+
+    // Worth of the heap that's left to play on
+    fn heap(&self, heap: &Heap, grid_size: GridSize) -> f32 {
+        let grid_width = grid_size.width;
+        let rows = heap.blocks.len() as i16 / grid_width;
+        let occupied = |x, y| heap.occupied(GridPos { x, y }, grid_width);
+
+        let heights = (0..grid_width)
+            .map(|x| {
+                (0..rows)
+                    .rev()
+                    .find(|&y| occupied(x, y))
+                    .map_or(0, |y| y + 1)
+            })
+            .collect::<Vec<_>>()
+        ;
+        if heights.iter().any(|&height| height > grid_size.height) {
+            return TOPPED_OUT;
+        }
+
+        let height = heights.iter().sum::<i16>();
+        let holes = heights
+            .iter()
+            .zip(0..)
+            .map(|(&height, x)| {
+                (0..height).filter(|&y| !occupied(x, y)).count()
+            })
+            .sum::<usize>()
+        ;
+        let bumpiness = heights
+            .windows(2)
+            .map(|pair| (pair[0] - pair[1]).abs())
+            .sum::<i16>()
+        ;
+        // walls count as being as high as they need to be
+        let wells = (0..heights.len())
+            .map(|x| {
+                let left = x.checked_sub(1).and_then(|x| heights.get(x));
+                let right = heights.get(x + 1);
+                let side = match (left, right) {
+                    (Some(&left), Some(&right)) => left.min(right),
+                    (Some(&side), None) | (None, Some(&side)) => side,
+                    (None, None) => heights[x],
+                };
+                (side - heights[x]).max(0)
+            })
+            .sum::<i16>()
+        ;
+        // a T pointing down fits, its bottom corners are taken, and there's
+        // something above it to spin it in under
+        let top = heights.iter().max().copied().unwrap_or_default();
+        let t_slots = (0..grid_width)
+            .flat_map(|x| (1..top).map(move |y| (x, y)))
+            .filter(|&(x, y)| {
+                [(x, y), (x - 1, y), (x + 1, y), (x, y - 1), (x, y + 1)]
+                    .into_iter()
+                    .all(|(x, y)| !occupied(x, y))
+                    && occupied(x - 1, y - 1)
+                    && occupied(x + 1, y - 1)
+                    && (occupied(x - 1, y + 1) || occupied(x + 1, y + 1))
+            })
+            .count()
+        ;
+
+        self.holes * holes as f32
+            + self.bumpiness * bumpiness as f32
+            + self.height * height as f32
+            + self.wells * wells as f32
+            + self.t_slots * t_slots as f32
+    }
+}
+
+impl Default for BotWeights {
+    fn default() -> Self {
+        Self {
+            holes: -4.0,
+            bumpiness: -0.3,
+            height: -0.5,
+            wells: -0.3,
+            clears: vec![0.0, -1.0, -0.5, 0.5, 6.0],
+            t_spins: vec![0.0, 4.0, 10.0, 14.0],
+            t_slots: 1.5,
+        }
+    }
+}
+
+// Players whose boards are played by a bot rather than from the keyboard,
+// and how it plays
+#[derive(Resource)]
+pub struct Bots {
+    pub players: Vec<usize>,
+    pub weights: BotWeights,
+    // previewed pieces to think ahead to, up to `MAX_LOOKAHEAD`
+    pub lookahead: usize,
+    // by player, for the boards being played
+    pub playing: HashMap<usize, Bot>,
+}
+
+// A board's bot, carrying out the placement it picked for the current piece
+#[derive(Default)]
+pub struct Bot {
+    // steps left to the placement, along with the number of pieces placed
+    // before the piece it's for
+    plan: Option<(u32, VecDeque<Step>)>,
+    // tapped on the last tick acted on, to be released on the next one
+    tapped: Vec<Action>,
+    // row that the piece's origin is being soft dropped to
+    soft_drop_to: Option<i16>,
+    // ticks left until what was last pressed takes effect
+    wait: u64,
+}
+
+
+impl Bot {
+    // Pick a placement over again, as the game went differently than it did
+    // when the current one was picked; pressed actions still get released
+    pub fn replan(&mut self) {
+        self.plan = None;
+    }
+}
+
+
+// Place a piece on a copy of the heap, clearing any lines it completes;
+// returns the heap along with the number of lines cleared
+fn place(heap: &Heap, blocks: &[GridPos], grid_width: i16) -> (Heap, usize) {
+    let mut heap = heap.clone();
+    for pos in blocks {
+        let idx = (pos.x + pos.y * grid_width) as usize;
+        heap.blocks[idx] = HeapEntry::Occupied;
+    }
+    let lines = heap.clear_full_rows(grid_width).len();
+    (heap, lines)
+}
+
+// How good the best of a piece's placements is, thinking ahead to the
+// pieces coming after it, along with the placement itself
+fn best_placement(
+    weights: &BotWeights,
+    heap: &Heap,
+    grid_size: GridSize,
+    options: Vec<Placement>,
+    coming: &[PieceKind],
+) -> Option<(f32, Placement)> {
+    let mut options = options
+        .into_iter()
+        .map(|placement| {
+            let (heap, lines) = place(heap, &placement.blocks, grid_size.width);
+            let cleared = weights.clear(lines, placement.t_spin);
+            let value = cleared + weights.heap(&heap, grid_size);
+            (value, cleared, heap, placement)
+        })
+        .collect::<Vec<_>>()
+    ;
+    // the first of equally good placements wins, to stay deterministic
+    options.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+    let Some((next, coming)) = coming.split_first() else {
+        return options
+            .into_iter()
+            .next()
+            .map(|(value, _, _, placement)| (value, placement))
+        ;
+    };
+    options
+        .into_iter()
+        .take(BEAM_WIDTH)
+        .map(|(value, cleared, heap, placement)| {
+            if value <= TOPPED_OUT {
+                return (value, placement);
+            }
+            let options = placements(&heap, *next, grid_size);
+            let ahead =
+                best_placement(weights, &heap, grid_size, options, coming)
+                    .map_or(TOPPED_OUT, |(value, _)| value)
+            ;
+            (cleared + ahead, placement)
+        })
+        .reduce(|best, option| if option.0 > best.0 { option } else { best })
+}
+
+
+// Have each bot pick a placement for its piece once it shows up (or put the
+// piece on hold, if what comes out of hold can be placed better), and press
+// its way there, one step per tick; the actions go through the same queue as
+// those from the keyboard
+pub fn drive_bots(
+    clock: Res<GameClock>,
+    bots: Option<ResMut<Bots>>,
+    session: Option<Res<NetSession>>,
+    playback: Option<Res<Playback>>,
+    mut queue: ResMut<InputQueue>,
+    boards: Query<(
+        Entity,
+        &Board,
+        ChangeTrackers<Board>,
+        &GridSize,
+        &Heap,
+        &Origin,
+        &ActivePiece,
+        &Stats,
+        (&Randomizer, &Hold, Option<&PieceQueue>),
+    )>,
+    blocks: Query<(&Parent, &GridPos), With<Block>>,
+) {
+    let Some(mut bots) = bots else { return };
+    let bots = &mut *bots;
+    // a replay has the bot's actions already
+    if playback.is_some() {
+        return;
+    }
+    // over the network, actions only take effect a few ticks later, and a
+    // tick that's run again has had them sent already
+    let delay = match &session {
+        Some(session) if session.rerun(clock.tick) => return,
+        Some(_) => INPUT_DELAY,
+        None => 0,
+    };
+
+    for (
+        board,
+        &Board { player },
+        board_changes,
+        &grid_size,
+        heap,
+        origin,
+        piece,
+        stats,
+        (randomizer, hold, pieces),
+    ) in boards.iter() {
+        // over the network, only the board on this end can be played
+        let local = session
+            .as_ref()
+            .is_none_or(|session| session.player == player)
+        ;
+        if !local || !bots.players.contains(&player) {
+            continue;
+        }
+        // a new game's board starts off with a new bot
+        if board_changes.is_added() {
+            bots.playing.remove(&player);
+        }
+        let bot = bots.playing.entry(player).or_default();
+
+        let grid_width = grid_size.width;
+        let mut press = |action, pressed| {
+            queue.0.push((player, action, pressed));
+        };
+
+        for action in bot.tapped.drain(..) {
+            press(action, false);
+        }
+        if bot.wait > 0 {
+            bot.wait -= 1;
+            continue;
+        }
+
+        let positions = blocks
+            .iter()
+            .filter(|(parent, _)| parent.get() == board)
+            .map(|(_, &pos)| pos)
+            .collect::<Vec<_>>()
+        ;
+        // between pieces
+        if positions.is_empty() {
+            if bot.soft_drop_to.take().is_some() {
+                press(Action::SoftDrop, false);
+            }
+            continue;
+        }
+
+        let planned = bot.plan
+            .as_ref()
+            .is_some_and(|(placed, _)| *placed == stats.pieces)
+        ;
+        if !planned {
+            let lookahead = bots.lookahead;
+            // one more, in case holding brings the first of them out
+            let coming = coming(randomizer, pieces, lookahead + 1);
+            let ahead = |coming: &[PieceKind]| {
+                coming[..lookahead.min(coming.len())].to_vec()
+            };
+            // the piece may have moved already, if the plan was dropped
+            let options =
+                placements_from(heap, grid_width, &positions, *origin, *piece);
+            let best = best_placement(
+                &bots.weights,
+                heap,
+                grid_size,
+                options,
+                &ahead(&coming),
+            );
+            // whatever comes out of hold instead, and the pieces after it
+            let unheld = match hold.piece {
+                Some(kind) => Some((kind, &coming[..])),
+                None => coming.split_first().map(|(&kind, rest)| (kind, rest)),
+            };
+            let held = unheld
+                .filter(|_| !hold.used)
+                .and_then(|(kind, coming)| {
+                    let options = placements(heap, kind, grid_size);
+                    let coming = ahead(coming);
+                    let weights = &bots.weights;
+                    best_placement(weights, heap, grid_size, options, &coming)
+                })
+            ;
+            let value = |best: &Option<(f32, Placement)>| {
+                best.as_ref().map_or(TOPPED_OUT, |(value, _)| *value)
+            };
+            // the piece out of hold gets a plan of its own once it spawns
+            if value(&held) > value(&best) {
+                press(Action::Hold, true);
+                bot.tapped = vec![Action::Hold];
+                bot.wait = delay;
+                continue;
+            }
+            let path = best
+                .map(|(_, placement)| placement.path)
+                .unwrap_or_default()
+            ;
+            bot.plan = Some((stats.pieces, path.into()));
+        }
+
+        let landed = |blocks: &[GridPos]| {
+            !can_move(blocks, grid_width, MoveY::Down1, heap)
+        };
+        if let Some(row) = bot.soft_drop_to {
+            if origin.pos.y > row && !landed(&positions) {
+                continue;
+            }
+            bot.soft_drop_to = None;
+            press(Action::SoftDrop, false);
+        }
+
+        let Some((_, plan)) = &mut bot.plan else { continue };
+        let Some(&step) = plan.front() else { continue };
+        let mut tapped = Vec::new();
+        let mut soft_drop_to = None;
+        let mut done = true;
+        match step {
+            Step::Move(Motion::Left) => tapped.push(Action::Left),
+            Step::Move(Motion::Right) => tapped.push(Action::Right),
+            Step::Move(shift @ (Motion::DasLeft | Motion::DasRight)) => {
+                // tapped over and over, rather than held, so that it's known
+                // which tap is the last one
+                let (move_x, action) = match shift {
+                    Motion::DasLeft => (MoveX::Left, Action::Left),
+                    _ => (MoveX::Right, Action::Right),
+                };
+                if can_move(&positions, grid_width, move_x, heap) {
+                    tapped.push(action);
+                    let moved = positions
+                        .iter()
+                        .map(|&pos| pos + move_x.to_offset())
+                        .collect::<Vec<_>>()
+                    ;
+                    done = !can_move(&moved, grid_width, move_x, heap);
+                }
+            },
+            Step::Move(Motion::SoftDrop(rows)) => {
+                press(Action::SoftDrop, true);
+                soft_drop_to = Some(origin.pos.y - rows);
+            },
+            Step::Move(Motion::HardDrop) => tapped.push(Action::HardDrop),
+            Step::Rotate(rotate) => tapped.push(match rotate {
+                Rotate::Clockwise => Action::RotateClockwise,
+                Rotate::Counterclockwise => Action::RotateCounterclockwise,
+                Rotate::Half => Action::Rotate180,
+            }),
+        }
+        if done {
+            plan.pop_front();
+        }
+
+        for &action in &tapped {
+            press(action, true);
+        }
+        bot.tapped = tapped;
+        bot.soft_drop_to = soft_drop_to;
+        bot.wait = delay;
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+
+    const SIZE: GridSize =
+        GridSize { width: 10, height: 20, hidden_height: 4 };
+
+    // A heap from rows drawn from the top down, as puzzles are
+    fn heap(rows: &[&str]) -> Heap {
+        let mut heap = Heap::new(SIZE);
+        for (y, row) in rows.iter().rev().enumerate() {
+            for (x, cell) in row.chars().enumerate() {
+                if cell == '#' {
+                    heap.blocks[x + y * SIZE.width as usize] =
+                        HeapEntry::Occupied
+                    ;
+                }
+            }
+        }
+        heap
+    }
+
+    // The bot's pick for a piece, from where it spawns
+    fn best(heap: &Heap, kind: PieceKind, coming: &[PieceKind]) -> Placement {
+        let weights = BotWeights::default();
+        let options = placements(heap, kind, SIZE);
+        best_placement(&weights, heap, SIZE, options, coming)
+            .expect("nowhere to put the piece")
+            .1
+    }
+
+    fn blocks(placement: &Placement) -> Vec<(i16, i16)> {
+        let mut blocks = placement.blocks
+            .iter()
+            .map(|pos| (pos.x, pos.y))
+            .collect::<Vec<_>>()
+        ;
+        blocks.sort_unstable();
+        blocks
+    }
+
+    #[test]
+    fn holes_make_for_a_worse_heap() {
+        let weights = BotWeights::default();
+        let stacked = weights.heap(&heap(&["#.........", "#........."]), SIZE);
+        let covered = weights.heap(&heap(&["#.........", ".........."]), SIZE);
+        assert_eq!(covered, stacked + weights.holes);
+        assert!(covered < stacked);
+    }
+
+    #[test]
+    fn bots_take_a_tetris_that_is_there() {
+        let well = heap(&[
+            "#########.",
+            "#########.",
+            "#########.",
+            "#########.",
+        ]);
+        for coming in [&[][..], &[PieceKind::O]] {
+            let placement = best(&well, PieceKind::I, coming);
+            assert_eq!(blocks(&placement), [(9, 0), (9, 1), (9, 2), (9, 3)]);
+        }
+    }
+
+    #[test]
+    fn bots_fill_gaps_rather_than_cover_them() {
+        let gap = heap(&["#.#......."]);
+        let placement = best(&gap, PieceKind::T, &[]);
+        assert!(blocks(&placement).contains(&(1, 0)));
+    }
+}
@@ -0,0 +1,188 @@
+use bevy::prelude::*;
+use crate::grid::{GridPos, GridSize};
+use crate::heap::{Heap, LockEvent};
+use crate::input::{Action, Inputs};
+use crate::movement::DropEvent;
+use crate::piece::PieceKind;
+use crate::placement::{Motion, Step, placements};
+use crate::stats::Stats;
+
+
+// every press of these counts as an input, however long it's held for
+const FINESSE_ACTIONS: [Action; 5] = [
+    Action::Left,
+    Action::Right,
+    Action::RotateClockwise,
+    Action::RotateCounterclockwise,
+    Action::Rotate180,
+];
+
+// a piece was placed on a board using more inputs than it needed
+pub struct FinesseFaultEvent {
+    pub board: Entity,
+    pub used: u32,
+    pub needed: u32,
+}
+
+// point out finesse faults as soon as they're made, rather than only
+// counting them
+#[derive(Resource)]
+pub struct FinesseAlerts;
+
+// How the current piece has been handled so far
+#[derive(Clone, Component, Default)]
+pub struct PieceInputs {
+    inputs: u32,
+    // any extra inputs may have gone into a tuck or a spin, so the piece
+    // isn't judged
+    soft_dropped: bool,
+}
+
+// Columns and shape of the blocks, regardless of the rows they're in
+fn footprint(blocks: &[GridPos]) -> Vec<(i16, i16)> {
+    let bottom = blocks.iter().map(|pos| pos.y).min().unwrap_or_default();
+    let mut footprint = blocks
+        .iter()
+        .map(|pos| (pos.x, pos.y - bottom))
+        .collect::<Vec<_>>()
+    ;
+    footprint.sort_unstable();
+    footprint
+}
+
+// Fewest inputs that take a piece from where it spawns to the columns and
+// orientation of the given blocks, as if the field were empty (which is how
+// finesse is judged); none if it can't get there without soft dropping or
+// spinning it in
+pub fn min_inputs(
+    kind: PieceKind,
+    grid_size: GridSize,
+    target: &[GridPos],
+) -> Option<u32> {
+    let goal = footprint(target);
+    placements(&Heap::new(grid_size), kind, grid_size)
+        .into_iter()
+        .filter(|placement| footprint(&placement.blocks) == goal)
+        .filter(|placement| {
+            !placement.spin && placement.path.iter().all(|step| {
+                !matches!(step, Step::Move(Motion::SoftDrop(_)))
+            })
+        })
+        .map(|placement| placement.inputs)
+        .min()
+}
+
+
+// Judge each placed piece by the inputs it took
+pub fn finesse(
+    mut drop_events: EventReader<DropEvent>,
+    mut lock_events: EventReader<LockEvent>,
+    mut fault_notify: EventWriter<FinesseFaultEvent>,
+    mut boards: Query<(&GridSize, &Inputs, &mut PieceInputs, &mut Stats)>,
+) {
+    for (_, inputs, mut piece_inputs, _) in boards.iter_mut() {
+        piece_inputs.inputs += FINESSE_ACTIONS
+            .into_iter()
+            .filter(|&action| inputs.just_pressed(action))
+            .count() as u32
+        ;
+    }
+    for drop in drop_events.iter().filter(|drop| !drop.hard) {
+        if let Ok((_, _, mut piece_inputs, _)) = boards.get_mut(drop.board) {
+            piece_inputs.soft_dropped = true;
+        }
+    }
+
+    for lock in lock_events.iter() {
+        let board = lock.board;
+        let Ok((&grid_size, _, mut piece_inputs, mut stats)) =
+            boards.get_mut(board)
+        else {
+            continue;
+        };
+        let used = piece_inputs.inputs;
+        let soft_dropped = piece_inputs.soft_dropped;
+        *piece_inputs = PieceInputs::default();
+        if soft_dropped {
+            continue;
+        }
+
+        let Some(needed) = min_inputs(lock.kind, grid_size, &lock.blocks)
+        else {
+            continue;
+        };
+        if used > needed {
+            stats.finesse_faults += 1;
+            fault_notify.send(FinesseFaultEvent { board, used, needed });
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+
+    const SIZE: GridSize =
+        GridSize { width: 10, height: 20, hidden_height: 4 };
+
+    fn blocks(positions: [(i16, i16); 4]) -> Vec<GridPos> {
+        positions.map(|(x, y)| GridPos { x, y }).to_vec()
+    }
+
+    // The faults found when a piece locks after the given handling
+    fn faults(
+        kind: PieceKind,
+        target: &[GridPos],
+        inputs: u32,
+        soft_drop: bool,
+    ) -> u32 {
+        let mut world = World::new();
+        world.init_resource::<Events<DropEvent>>();
+        world.init_resource::<Events<LockEvent>>();
+        world.init_resource::<Events<FinesseFaultEvent>>();
+        let board = world.spawn((
+            SIZE,
+            Inputs::new(),
+            PieceInputs { inputs, soft_dropped: false },
+            Stats::default(),
+        )).id();
+
+        if soft_drop {
+            world.send_event(DropEvent { board, rows: 1, hard: false });
+        }
+        world.send_event(LockEvent {
+            board,
+            kind,
+            blocks: target.to_vec(),
+            t_spin: None,
+        });
+        SystemStage::single(finesse).run(&mut world);
+        world.get::<Stats>(board).unwrap().finesse_faults
+    }
+
+    #[test]
+    fn o_pieces_reach_the_wall_in_one_input() {
+        let wall = blocks([(0, 0), (1, 0), (0, 1), (1, 1)]);
+        assert_eq!(min_inputs(PieceKind::O, SIZE, &wall), Some(1));
+        // the row the piece ends up in doesn't matter
+        let raised = blocks([(0, 5), (1, 5), (0, 6), (1, 6)]);
+        assert_eq!(min_inputs(PieceKind::O, SIZE, &raised), Some(1));
+        let two_over = blocks([(2, 0), (3, 0), (2, 1), (3, 1)]);
+        assert_eq!(min_inputs(PieceKind::O, SIZE, &two_over), Some(2));
+    }
+
+    #[test]
+    fn extra_presses_are_faults() {
+        let wall = blocks([(0, 0), (1, 0), (0, 1), (1, 1)]);
+        assert_eq!(faults(PieceKind::O, &wall, 1, false), 0);
+        assert_eq!(faults(PieceKind::O, &wall, 2, false), 1);
+    }
+
+    #[test]
+    fn soft_dropped_pieces_are_not_judged() {
+        let wall = blocks([(0, 0), (1, 0), (0, 1), (1, 1)]);
+        assert_eq!(faults(PieceKind::O, &wall, 5, true), 0);
+    }
+}
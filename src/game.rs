@@ -0,0 +1,463 @@
+use bevy::prelude::*;
+use bevy::utils::Duration;
+use serde::{Deserialize, Serialize};
+use ::core::fmt;
+use crate::board::{Board, board_position};
+use crate::grid::{GridPos, GridSize};
+use crate::heap::{HEAP_COLOR, Heap, spawn_garbage};
+use crate::garbage::{GarbageRows, PendingGarbage, messy_holes};
+use crate::input::{Action, Inputs, InputQueue, MenuInputs};
+use crate::movement::{
+    GarbageTimer,
+    GravityTimer,
+    LockDelay,
+    MovementXTimer,
+    MovementYTimer,
+};
+use crate::piece::{
+    ActivePiece,
+    Hold,
+    Origin,
+    OriginMode,
+    OutOfPiecesEvent,
+    PieceKind,
+    PieceQueue,
+    Randomizer,
+    SpawnEvent,
+    spawn_block,
+};
+use crate::replay::{Playback, Recording, Replay};
+use crate::ruleset::Ruleset;
+use crate::level::Level;
+use crate::mode::{GameMode, GoalEvent, SURVIVAL_START_INTERVAL};
+use crate::net::NetSession;
+use crate::score::Score;
+use crate::stats::Stats;
+use crate::finesse::PieceInputs;
+use crate::tick::{GameClock, TICK};
+
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum GameState {
+    Menu,
+    Playing,
+    Paused,
+    GameOver,
+}
+
+// The ways in which the stack can grow out of the field, each of which can
+// be enabled by the ruleset (named as in the guideline)
+#[allow(clippy::enum_variant_names)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TopOut {
+    // a new piece overlaps the heap as it spawns
+    BlockOut,
+    // a piece locks entirely above the visible field
+    LockOut,
+    // a piece locks partly above the visible field
+    PartialLockOut,
+    // garbage pushes blocks out of the top of the heap
+    GarbageOut,
+}
+
+// a board's stack has topped out, which ends the game
+#[derive(Clone, Copy)]
+pub struct TopOutEvent {
+    pub board: Entity,
+    pub top_out: TopOut,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum GameOverReason {
+    TopOut(TopOut),
+    // the mode's goal has been reached
+    Finished,
+    // the puzzle's pieces were all used up without solving it
+    OutOfPieces,
+    // the given player outlasted everyone else
+    Won(usize),
+    // the last players standing topped out at once
+    Draw,
+    // the other end of a game over the network went away
+    Disconnected,
+    // the boards on either end of a game over the network no longer match
+    Desynced,
+}
+
+impl fmt::Display for GameOverReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::TopOut(TopOut::BlockOut) => write!(f, "Block out"),
+            Self::TopOut(TopOut::LockOut) => write!(f, "Lock out"),
+            Self::TopOut(TopOut::PartialLockOut) => {
+                write!(f, "Partial lock out")
+            },
+            Self::TopOut(TopOut::GarbageOut) => write!(f, "Garbage out"),
+            Self::Finished => write!(f, "Finished"),
+            Self::OutOfPieces => write!(f, "Out of pieces"),
+            Self::Won(player) => write!(f, "Player {} wins", player + 1),
+            Self::Draw => write!(f, "Draw"),
+            Self::Disconnected => write!(f, "Opponent disconnected"),
+            Self::Desynced => write!(f, "Out of sync"),
+        }
+    }
+}
+
+// the game in progress has come to an end
+pub struct GameOverEvent {
+    pub summary: GameSummary,
+}
+
+// how a finished game went, as played on the winner's board if there was
+// more than one
+#[derive(Debug, Clone, PartialEq, Resource, Serialize, Deserialize)]
+pub struct GameSummary {
+    pub reason: GameOverReason,
+    // the tick the game ended on
+    pub ticks: u64,
+    pub score: u64,
+    pub lines: u32,
+    pub level: u32,
+    pub pieces: u32,
+}
+
+impl GameSummary {
+    pub fn time(&self) -> Duration {
+        TICK * self.ticks as u32
+    }
+}
+
+// where games come from, both on launch and on every restart
+#[derive(Resource)]
+pub enum GameSource {
+    // played from the keyboard, with a fresh seed each time unless one is
+    // given
+    Live { seed: Option<u64> },
+    // played back from a recording
+    Replay(Box<Replay>),
+}
+
+
+// (Re)set everything about the game in progress to the start of a new game
+pub fn start_game(
+    commands: &mut Commands,
+    source: &GameSource,
+    ruleset: &Ruleset,
+    spawn_notify: &mut Events<SpawnEvent>,
+) {
+    let seed = match source {
+        GameSource::Live { seed } => {
+            let seed = seed.unwrap_or_else(rand::random);
+            commands.insert_resource(Recording::new(seed, ruleset.clone()));
+            seed
+        },
+        GameSource::Replay(replay) => {
+            commands.insert_resource(Playback::new(replay.as_ref().clone()));
+            replay.seed
+        },
+    };
+
+    commands.insert_resource(GameClock::default());
+    commands.insert_resource(InputQueue::default());
+
+    spawn_notify.clear();
+    for player in 0..ruleset.mode.players() {
+        let board = spawn_board(commands, ruleset, seed, player);
+        spawn_notify.send(SpawnEvent { board, held: false });
+    }
+}
+
+// Set up a player's board for a new game
+fn spawn_board(
+    commands: &mut Commands,
+    ruleset: &Ruleset,
+    seed: u64,
+    player: usize,
+) -> Entity {
+    let players = ruleset.mode.players();
+    let board = commands
+        .spawn((
+            Board { player },
+            ruleset.grid_size,
+            SpatialBundle::from_transform(
+                board_position(player, players, ruleset.grid_size),
+            ),
+        ))
+        .id()
+    ;
+    // the first player's pieces come just as they would playing alone
+    let mut randomizer = Randomizer::new(seed.wrapping_add(player as u64));
+
+    let grid_width = ruleset.grid_size.width;
+    let mut heap = match &ruleset.puzzle {
+        Some(puzzle) => {
+            let queue = puzzle.queue.iter().copied().collect();
+            commands.entity(board).insert(PieceQueue(queue));
+            for pos in puzzle.blocks() {
+                spawn_block(commands, board, pos, HEAP_COLOR);
+            }
+            puzzle.heap(ruleset.grid_size)
+        },
+        None => Heap::new(ruleset.grid_size),
+    };
+    let holes = match ruleset.mode {
+        GameMode::Cheese { rows, messiness } => {
+            // a replay's ruleset hasn't been checked for fitting in the field
+            let rows = rows.min(ruleset.grid_size.height as u32 - 1);
+            messy_holes(&mut randomizer, rows, grid_width, messiness)
+        },
+        _ => Vec::new(),
+    };
+    // cheese starts from an empty heap, which the rows then fit into
+    // without pushing anything out
+    heap.push_garbage(&holes, grid_width);
+    spawn_garbage(commands, board, &holes, grid_width);
+
+    commands.entity(board).insert((
+        heap,
+        randomizer,
+        Hold::default(),
+        GarbageRows(holes.len() as u32),
+        PendingGarbage::default(),
+        // placeholder values
+        Origin {
+            pos: GridPos { x: 0, y: 0 },
+            mode: OriginMode::PointCentered,
+        },
+        ActivePiece {
+            kind: PieceKind::I,
+            rotation: 0,
+            last_kick: None,
+        },
+        Inputs::new(),
+        (
+            GravityTimer::new(ruleset.gravity.row_time(ruleset.start_level)),
+            MovementXTimer::new(ruleset.auto_repeat),
+            MovementYTimer::new(ruleset.soft_drop),
+            GarbageTimer::new(SURVIVAL_START_INTERVAL),
+            LockDelay::new(ruleset.lock_delay),
+        ),
+        Score::default(),
+        Level(ruleset.start_level),
+        Stats::default(),
+        PieceInputs::default(),
+    ));
+    board
+}
+
+
+// Wrap up the game once it's lost, or once the mode's goal is reached; with
+// several players, the first to top out loses
+pub fn end_game(
+    mut commands: Commands,
+    ruleset: Res<Ruleset>,
+    clock: Res<GameClock>,
+    mut top_out_events: EventReader<TopOutEvent>,
+    mut goal_events: EventReader<GoalEvent>,
+    mut out_of_pieces_events: EventReader<OutOfPiecesEvent>,
+    mut game_over_notify: EventWriter<GameOverEvent>,
+    mut spawn_notify: EventWriter<SpawnEvent>,
+    session: Option<ResMut<NetSession>>,
+    mut boards: Query<(
+        Entity,
+        &Board,
+        &Score,
+        &Level,
+        &Stats,
+        &GridSize,
+        &mut Heap,
+        &mut GarbageRows,
+    )>,
+    blocks: Query<(Entity, &Parent), With<GridPos>>,
+) {
+    let mut topped_out = Vec::<TopOutEvent>::new();
+    for &top_out in top_out_events.iter() {
+        if topped_out.iter().all(|other| other.board != top_out.board) {
+            topped_out.push(top_out);
+        }
+    }
+    let goal_reached = goal_events.iter().next().map(|goal| goal.board);
+    let out_of_pieces = out_of_pieces_events
+        .iter()
+        .next()
+        .map(|out_of_pieces| out_of_pieces.board)
+    ;
+
+    // zen carries on with an empty board
+    if ruleset.mode == GameMode::Zen {
+        for TopOutEvent { board, .. } in topped_out {
+            let Ok((.., &grid_size, mut heap, mut garbage_rows)) =
+                boards.get_mut(board)
+            else {
+                continue;
+            };
+            blocks
+                .iter()
+                .filter(|(_, parent)| parent.get() == board)
+                .for_each(|(entity, _)| {
+                    commands.entity(entity).despawn_recursive();
+                })
+            ;
+            *heap = Heap::new(grid_size);
+            garbage_rows.0 = 0;
+            spawn_notify.send(SpawnEvent { board, held: false });
+        }
+        return;
+    }
+
+    let standing = boards
+        .iter()
+        .filter(|(board, ..)| {
+            topped_out.iter().all(|top_out| top_out.board != *board)
+        })
+        .map(|(board, &Board { player }, ..)| (board, player))
+        .collect::<Vec<_>>()
+    ;
+    let (reason, board) = match topped_out.first() {
+        Some(_) if ruleset.mode.players() > 1 => match standing[..] {
+            [(winner, player)] => (GameOverReason::Won(player), winner),
+            _ => (GameOverReason::Draw, topped_out[0].board),
+        },
+        Some(&TopOutEvent { board, top_out }) => {
+            (GameOverReason::TopOut(top_out), board)
+        },
+        None => match (goal_reached, out_of_pieces) {
+            (Some(board), _) => (GameOverReason::Finished, board),
+            (None, Some(board)) => (GameOverReason::OutOfPieces, board),
+            (None, None) => return,
+        },
+    };
+    let Ok((_, _, score, level, stats, ..)) = boards.get(board) else {
+        return;
+    };
+
+    let summary = GameSummary {
+        reason,
+        ticks: clock.tick,
+        score: score.points,
+        lines: score.lines,
+        level: level.0,
+        pieces: stats.pieces,
+    };
+
+    // over the network, a game can't end on a guess of the other end's
+    // actions, so it waits to see whether the guess was right
+    match session {
+        Some(mut session) if !session.confirmed(clock.tick) => {
+            session.hold_game_over(summary);
+        },
+        _ => game_over_notify.send(GameOverEvent { summary }),
+    }
+}
+
+pub fn game_over(
+    mut commands: Commands,
+    mut game_over_events: EventReader<GameOverEvent>,
+    mut state: ResMut<State<GameState>>,
+) {
+    let Some(game_over) = game_over_events.iter().last() else { return };
+
+    commands.insert_resource(game_over.summary.clone());
+    let _ = state.set(GameState::GameOver);
+}
+
+pub fn start_from_menu(
+    mut commands: Commands,
+    menu_inputs: Res<MenuInputs>,
+    source: Res<GameSource>,
+    ruleset: Res<Ruleset>,
+    mut state: ResMut<State<GameState>>,
+    mut spawn_notify: ResMut<Events<SpawnEvent>>,
+    boards: Query<Entity, With<Board>>,
+) {
+    if *state.current() != GameState::Menu
+        || !menu_inputs.just_pressed(Action::Confirm)
+    {
+        return;
+    }
+
+    clear_boards(&mut commands, &boards);
+    start_game(&mut commands, &source, &ruleset, &mut spawn_notify);
+    let _ = state.set(GameState::Playing);
+}
+
+pub fn pause(
+    menu_inputs: Res<MenuInputs>,
+    session: Option<Res<NetSession>>,
+    mut state: ResMut<State<GameState>>,
+) {
+    // the other end wouldn't wait
+    if !menu_inputs.just_pressed(Action::Pause) || session.is_some() {
+        return;
+    }
+
+    let next = match state.current() {
+        GameState::Playing => GameState::Paused,
+        GameState::Paused => GameState::Playing,
+        GameState::Menu | GameState::GameOver => return,
+    };
+    // a transition may already be queued up, in which case it wins
+    let _ = state.set(next);
+}
+
+pub fn restart(
+    mut commands: Commands,
+    menu_inputs: Res<MenuInputs>,
+    source: Res<GameSource>,
+    ruleset: Res<Ruleset>,
+    recording: Option<Res<Recording>>,
+    session: Option<Res<NetSession>>,
+    mut state: ResMut<State<GameState>>,
+    mut spawn_notify: ResMut<Events<SpawnEvent>>,
+    boards: Query<Entity, With<Board>>,
+) {
+    if *state.current() == GameState::Menu
+        || !menu_inputs.just_pressed(Action::Restart)
+        || session.is_some()
+    {
+        return;
+    }
+
+    abandon_game(&mut commands, recording);
+    clear_boards(&mut commands, &boards);
+    start_game(&mut commands, &source, &ruleset, &mut spawn_notify);
+
+    if *state.current() != GameState::Playing {
+        let _ = state.set(GameState::Playing);
+    }
+}
+
+pub fn back_to_menu(
+    mut commands: Commands,
+    menu_inputs: Res<MenuInputs>,
+    recording: Option<Res<Recording>>,
+    mut state: ResMut<State<GameState>>,
+) {
+    if !matches!(state.current(), GameState::Paused | GameState::GameOver)
+        || !menu_inputs.just_pressed(Action::Menu)
+    {
+        return;
+    }
+
+    abandon_game(&mut commands, recording);
+    let _ = state.set(GameState::Menu);
+}
+
+// an unfinished game is still worth keeping a recording of
+fn abandon_game(commands: &mut Commands, recording: Option<Res<Recording>>) {
+    if let Some(recording) = recording {
+        recording.save();
+        commands.remove_resource::<Recording>();
+    }
+}
+
+// the boards of the last game, blocks and all, being their children
+fn clear_boards(
+    commands: &mut Commands,
+    boards: &Query<Entity, With<Board>>,
+) {
+    boards
+        .iter()
+        .for_each(|entity| commands.entity(entity).despawn_recursive())
+    ;
+}
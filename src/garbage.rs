@@ -0,0 +1,254 @@
+use bevy::prelude::*;
+use bevy::utils::Duration;
+use rand::Rng;
+use ::std::collections::VecDeque;
+use crate::board::Board;
+use crate::grid::GridSize;
+use crate::heap::GarbageEvent;
+use crate::mode::{GameMode, SURVIVAL_MIN_INTERVAL};
+use crate::movement::GarbageTimer;
+use crate::piece::Randomizer;
+use crate::ruleset::Ruleset;
+use crate::score::ScoreEvent;
+use crate::tick::{GameClock, TICK};
+
+
+// Rows at the bottom of the heap that came in as garbage, however much of
+// them has been filled in since
+#[derive(Clone, Component, Default)]
+pub struct GarbageRows(pub u32);
+
+// Garbage sent by opponents that's yet to land, oldest first
+#[derive(Clone, Component, Default)]
+pub struct PendingGarbage(VecDeque<IncomingGarbage>);
+
+#[derive(Clone)]
+struct IncomingGarbage {
+    lines: u32,
+    // game time at which it lands
+    lands_at: Duration,
+}
+
+impl PendingGarbage {
+    // total lines on their way
+    pub fn lines(&self) -> u32 {
+        self.0.iter().map(|incoming| incoming.lines).sum()
+    }
+
+    // Offset lines of incoming garbage with lines of attack, oldest garbage
+    // first, and return what's left of the attack
+    fn cancel(&mut self, mut attack: u32) -> u32 {
+        while let Some(incoming) = self.0.front_mut() {
+            if attack < incoming.lines {
+                incoming.lines -= attack;
+                return 0;
+            }
+            attack -= incoming.lines;
+            self.0.pop_front();
+        }
+        attack
+    }
+}
+
+// Hole columns for rows of garbage, from the bottom up; each row's hole is
+// right above the one below it unless the messiness, as a percentage, has
+// it move elsewhere
+pub fn messy_holes(
+    randomizer: &mut Randomizer,
+    rows: u32,
+    grid_width: i16,
+    messiness: u8,
+) -> Vec<i16> {
+    let mut holes = Vec::with_capacity(rows as usize);
+    let mut hole = randomizer.rng.gen_range(0..grid_width);
+    for row in 0..rows {
+        if row > 0 && randomizer.rng.gen_range(0..100) < messiness {
+            // anywhere but where it was
+            let shift = randomizer.rng.gen_range(1..grid_width);
+            hole = (hole + shift) % grid_width;
+        }
+        holes.push(hole);
+    }
+    holes
+}
+
+
+// Have garbage rise on a timer, every time a little sooner than the last
+pub fn rise_garbage(
+    ruleset: Res<Ruleset>,
+    mut garbage_notify: EventWriter<GarbageEvent>,
+    mut boards: Query<(
+        Entity,
+        &GridSize,
+        &mut Randomizer,
+        &mut GarbageTimer,
+    )>,
+) {
+    if ruleset.mode != GameMode::Survival {
+        return;
+    }
+
+    for (
+        board,
+        grid_size,
+        mut randomizer,
+        mut garbage_timer,
+    ) in boards.iter_mut() {
+        garbage_timer.tick(TICK);
+        let rows = garbage_timer.times_finished_this_tick();
+        if rows == 0 {
+            continue;
+        }
+
+        let holes = messy_holes(&mut randomizer, rows, grid_size.width, 100);
+        garbage_notify.send(GarbageEvent { board, holes });
+
+        let interval = garbage_timer.duration();
+        let interval = (interval - interval / 30).max(SURVIVAL_MIN_INTERVAL);
+        garbage_timer.set_duration(interval);
+    }
+}
+
+// Send the garbage that each board's clears are worth on its way to the
+// other boards, once it has cancelled what's on its way to the board itself;
+// boards attacking each other on the same tick each cancel only what was sent
+// before it, and both attacks go through
+pub fn send_garbage(
+    ruleset: Res<Ruleset>,
+    clock: Res<GameClock>,
+    mut score_events: EventReader<ScoreEvent>,
+    mut boards: Query<(Entity, &Board, &mut PendingGarbage)>,
+) {
+    if ruleset.mode.players() < 2 {
+        score_events.clear();
+        return;
+    }
+
+    let mut sent = Vec::<(Entity, u32)>::new();
+    for event in score_events.iter() {
+        let lines = ruleset.attack.attack(event.kind);
+        if lines == 0 {
+            continue;
+        }
+        match sent.iter_mut().find(|(board, _)| *board == event.board) {
+            Some((_, sent)) => *sent += lines,
+            None => sent.push((event.board, lines)),
+        }
+    }
+
+    let mut sent = sent
+        .into_iter()
+        .filter_map(|(sender, lines)| {
+            let (_, board, mut pending) = boards.get_mut(sender).ok()?;
+            let lines = pending.cancel(lines);
+            (lines > 0).then_some((board.player, sender, lines))
+        })
+        .collect::<Vec<_>>()
+    ;
+    // in an order that's the same wherever the game is played
+    sent.sort_unstable_by_key(|&(player, ..)| player);
+
+    let lands_at = clock.time() + ruleset.garbage_delay;
+    for (_, sender, lines) in sent {
+        for (board, _, mut pending) in boards.iter_mut() {
+            if board != sender {
+                pending.0.push_back(IncomingGarbage { lines, lands_at });
+            }
+        }
+    }
+}
+
+// Have the garbage that's done waiting rise into the heap, each attack as a
+// single clean column however many lines it is
+pub fn land_garbage(
+    clock: Res<GameClock>,
+    mut garbage_notify: EventWriter<GarbageEvent>,
+    mut boards: Query<(
+        Entity,
+        &GridSize,
+        &mut Randomizer,
+        &mut PendingGarbage,
+    )>,
+) {
+    for (board, grid_size, mut randomizer, mut pending) in boards.iter_mut() {
+        let mut holes = Vec::new();
+        while let Some(incoming) = pending.0
+            .front()
+            .filter(|incoming| incoming.lands_at <= clock.time())
+        {
+            let lines = incoming.lines;
+            let width = grid_size.width;
+            holes.extend(messy_holes(&mut randomizer, lines, width, 0));
+            pending.0.pop_front();
+        }
+        if !holes.is_empty() {
+            garbage_notify.send(GarbageEvent { board, holes });
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use bevy::ecs::schedule::{Stage, SystemStage};
+    use super::*;
+    use crate::score::ScoreKind;
+
+
+    fn pending(lines: &[u32]) -> PendingGarbage {
+        PendingGarbage(
+            lines
+                .iter()
+                .map(|&lines| IncomingGarbage { lines, lands_at: TICK })
+                .collect()
+        )
+    }
+
+    fn lines(pending: &PendingGarbage) -> Vec<u32> {
+        pending.0.iter().map(|incoming| incoming.lines).collect()
+    }
+
+    #[test]
+    fn attacks_cancel_the_oldest_garbage_first() {
+        let mut garbage = pending(&[2, 3, 4]);
+        assert_eq!(garbage.cancel(4), 0);
+        assert_eq!(lines(&garbage), [1, 4]);
+        assert_eq!(garbage.lines(), 5);
+
+        assert_eq!(garbage.cancel(7), 2);
+        assert_eq!(garbage.lines(), 0);
+        assert_eq!(pending(&[]).cancel(3), 3);
+    }
+
+    #[test]
+    fn attacks_on_the_same_tick_both_go_through() {
+        let mut world = World::new();
+        world.insert_resource(Ruleset {
+            mode: GameMode::Versus,
+            ..Ruleset::default()
+        });
+        world.insert_resource(GameClock::default());
+        world.init_resource::<Events<ScoreEvent>>();
+        let boards = [(0, &[][..]), (1, &[1][..])].map(|(player, lines)| {
+            world.spawn((Board { player }, pending(lines))).id()
+        });
+
+        // a tetris (4 lines) each, the second board cancelling what it had
+        // coming first
+        let tetris = ScoreKind::Clear {
+            lines: 4,
+            t_spin: None,
+            back_to_back: false,
+        };
+        let mut score_events = world.resource_mut::<Events<ScoreEvent>>();
+        for board in boards.into_iter().rev() {
+            score_events.send(ScoreEvent { board, kind: tetris, points: 0 });
+        }
+        SystemStage::single(send_garbage).run(&mut world);
+
+        let pending = boards.map(|board| {
+            lines(world.get::<PendingGarbage>(board).unwrap())
+        });
+        assert_eq!(pending, [vec![3], vec![4]]);
+    }
+}
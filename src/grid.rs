@@ -1,16 +1,25 @@
 use bevy::ecs::{
     world::Mut,
     component::Component,
-    system::Resource,
 };
 use ::core::ops::{Add, AddAssign};
 use ::core::borrow::Borrow;
+use serde::{Deserialize, Serialize};
 
 
-#[derive(Clone, Copy, Resource)]
+#[derive(Debug, Clone, Copy, Component, Serialize, Deserialize)]
 pub struct GridSize {
     pub width: i16,
+    // height of the visible field
     pub height: i16,
+    // rows above the visible field, which pieces spawn into and can lock in
+    pub hidden_height: i16,
+}
+
+impl GridSize {
+    pub fn total_height(&self) -> i16 {
+        self.height + self.hidden_height
+    }
 }
 
 // Holds a block's position within a piece for rotation
@@ -40,12 +49,12 @@ impl AddAssign<(i16, i16)> for GridPos {
 
 impl Borrow<GridPos> for &Mut<'_, GridPos> {
     fn borrow(&self) -> &GridPos {
-        &*self
+        self
     }
 }
 
 impl Borrow<GridPos> for &&GridPos {
     fn borrow(&self) -> &GridPos {
-        *self
+        self
     }
 }
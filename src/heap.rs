@@ -1,60 +1,495 @@
 use bevy::prelude::*;
-use bevy::app::AppExit;
 use crate::grid::{GridSize, GridPos};
-use crate::piece::{Block, SpawnEvent};
-use crate::movement::{MoveY, can_move};
+use crate::game::{TopOut, TopOutEvent};
+use crate::input::{Action, Inputs};
+use crate::ruleset::Ruleset;
+use crate::piece::{
+    ActivePiece,
+    Block,
+    Origin,
+    PieceKind,
+    SpawnEvent,
+    spawn_block,
+};
+use crate::movement::{LockDelay, MoveNeutral, MoveY, can_move};
+use crate::rotation::{TSpin, t_spin};
+use crate::garbage::GarbageRows;
+use crate::tick::TICK;
 
 
-#[derive(Resource)]
+// color of blocks that didn't come from a piece, like those of a puzzle or
+// garbage
+pub const HEAP_COLOR: Color = Color::rgb(0.4, 0.4, 0.4);
+
+#[derive(Clone, Component)]
 pub struct Heap {
     pub blocks: Vec<HeapEntry>,
 }
 
+impl Heap {
+    pub fn new(grid_size: GridSize) -> Self {
+        Self {
+            blocks: vec![
+                HeapEntry::Vacant;
+                (grid_size.width * grid_size.total_height()) as usize
+            ],
+        }
+    }
+
+    // whether the cell is taken, anything outside of the heap counting as such
+    pub fn occupied(&self, pos: GridPos, grid_width: i16) -> bool {
+        pos.x < 0 || pos.x >= grid_width || pos.y < 0
+            || !matches!(
+                self.blocks.get((pos.x + pos.y * grid_width) as usize),
+                Some(HeapEntry::Vacant),
+            )
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.iter().all(|entry| matches!(entry, HeapEntry::Vacant))
+    }
+
+    // Remove all full rows, moving the rows above them down; returns the
+    // (original) indices of the removed rows, from the bottom up
+    pub fn clear_full_rows(&mut self, grid_width: i16) -> Vec<i16> {
+        let width = grid_width as usize;
+        let height = self.blocks.len() / width;
+
+        let (full, kept): (Vec<_>, Vec<_>) = self.blocks
+            .chunks(width)
+            .enumerate()
+            .partition(|(_, row)| {
+                row.iter().all(|entry| matches!(entry, HeapEntry::Occupied))
+            })
+        ;
+        let cleared = full
+            .into_iter()
+            .map(|(y, _)| y as i16)
+            .collect::<Vec<_>>()
+        ;
+
+        let mut blocks = kept
+            .into_iter()
+            .flat_map(|(_, row)| row.iter().cloned())
+            .collect::<Vec<_>>()
+        ;
+        blocks.resize(width * height, HeapEntry::Vacant);
+        self.blocks = blocks;
+
+        cleared
+    }
+
+    // Push garbage rows in from the bottom, one for each given hole column,
+    // with the first one ending up at the bottom; returns whether anything
+    // was pushed out of the top of the heap
+    pub fn push_garbage(&mut self, holes: &[i16], grid_width: i16) -> bool {
+        let width = grid_width as usize;
+        let pushed = (holes.len() * width).min(self.blocks.len());
+
+        let overflow = self.blocks[self.blocks.len() - pushed..]
+            .iter()
+            .any(|entry| matches!(entry, HeapEntry::Occupied))
+        ;
+
+        let garbage = holes
+            .iter()
+            .flat_map(|&hole| (0..grid_width).map(move |x| {
+                if x == hole { HeapEntry::Vacant } else { HeapEntry::Occupied }
+            }))
+        ;
+        self.blocks = garbage
+            .chain(self.blocks.iter().cloned())
+            .take(self.blocks.len())
+            .collect()
+        ;
+
+        overflow
+    }
+}
+
 #[derive(Clone)]
 pub enum HeapEntry {
     Vacant,
     Occupied,
 }
 
+// a piece has been locked into the board's heap
+pub struct LockEvent {
+    pub board: Entity,
+    pub kind: PieceKind,
+    pub blocks: Vec<GridPos>,
+    pub t_spin: Option<TSpin>,
+}
+
+// Sent for every locked piece, whether or not it completed any rows
+pub struct LineClearEvent {
+    pub board: Entity,
+    pub lines: u8,
+    pub t_spin: Option<TSpin>,
+}
+
+// a clear has left nothing of the heap
+pub struct PerfectClearEvent {
+    pub board: Entity,
+    pub lines: u8,
+}
+
+// garbage rows are to be pushed into the bottom of the board's heap, with a
+// hole in each of the given columns
+#[derive(Clone)]
+pub struct GarbageEvent {
+    pub board: Entity,
+    pub holes: Vec<i16>,
+}
+
 
 pub fn lock(
     mut commands: Commands,
-    grid_size: Res<GridSize>,
-    mut heap: ResMut<Heap>,
-    mut lose_notify: EventWriter<AppExit>,
+    ruleset: Res<Ruleset>,
+    mut top_out_notify: EventWriter<TopOutEvent>,
+    mut lock_notify: EventWriter<LockEvent>,
     mut spawn_notify: EventWriter<SpawnEvent>,
-    tetromino: Query<(Entity, &GridPos), With<Block>>,
+    mut boards: Query<(
+        Entity,
+        &GridSize,
+        &ActivePiece,
+        &Origin,
+        &Inputs,
+        &mut LockDelay,
+        &mut Heap,
+    )>,
+    tetromino: Query<(Entity, &Parent, &GridPos), With<Block>>,
+) {
+    for (
+        board,
+        grid_size,
+        piece,
+        origin,
+        inputs,
+        mut lock_delay,
+        mut heap,
+    ) in boards.iter_mut() {
+        let grid_width = grid_size.width;
+        let (block_entities, block_pos): (Vec<_>, Vec<&GridPos>) = tetromino
+            .iter()
+            .filter(|(_, parent, _)| parent.get() == board)
+            .map(|(entity, _, pos)| (entity, pos))
+            .unzip()
+        ;
+        // a piece spawned this tick only shows up once the tick is over
+        if block_pos.is_empty() {
+            continue;
+        }
+
+        // getting lower than ever gives the piece its full time (and all of
+        // its resets) back, and any other move or rotation starts the timer
+        // over while there are resets left
+        let position = (origin.pos.x, origin.pos.y, piece.rotation);
+        if origin.pos.y < lock_delay.lowest {
+            lock_delay.lowest = origin.pos.y;
+            lock_delay.resets = 0;
+            lock_delay.timer.reset();
+        } else if lock_delay.last != Some(position)
+            && lock_delay.resets < ruleset.lock_resets
+        {
+            lock_delay.resets += 1;
+            lock_delay.timer.reset();
+        }
+        lock_delay.last = Some(position);
+
+        // the timer only runs while the piece rests on something, and a hard
+        // drop doesn't wait for it
+        if can_move(&block_pos, grid_width, MoveY::Down1, &heap) {
+            continue;
+        }
+        lock_delay.timer.tick(TICK);
+        if !lock_delay.timer.finished()
+            && !inputs.just_pressed(Action::HardDrop)
+        {
+            continue;
+        }
+        lock_delay.clear();
+
+        // the piece stays where it is, whether or not the game goes on
+        block_entities
+            .into_iter()
+            .for_each(|entity| {
+                commands.entity(entity).remove::<Block>();
+            })
+        ;
+
+        let above_field = block_pos
+            .iter()
+            .filter(|pos| pos.y >= grid_size.height)
+            .count()
+        ;
+        let fits_heap = block_pos.iter().all(|pos| {
+            ((pos.x + pos.y * grid_width) as usize) < heap.blocks.len()
+        });
+        let top_out = match above_field {
+            0 => None,
+            n if n == block_pos.len() => Some(TopOut::LockOut),
+            _ => Some(TopOut::PartialLockOut),
+        };
+        if let Some(top_out) = top_out
+            .filter(|&top_out| !fits_heap || ruleset.top_out.enabled(top_out))
+        {
+            top_out_notify.send(TopOutEvent { board, top_out });
+            continue;
+        }
+
+        spawn_notify.send(SpawnEvent { board, held: false });
+        lock_notify.send(LockEvent {
+            board,
+            kind: piece.kind,
+            blocks: block_pos.iter().map(|&&pos| pos).collect(),
+            t_spin: t_spin(piece, origin, &heap, grid_width),
+        });
+
+        block_pos
+            .into_iter()
+            .for_each(|pos: &GridPos| {
+                let idx = pos.x + pos.y * grid_width;
+                // mark position in heap as occupied
+                heap.blocks[idx as usize] = HeapEntry::Occupied;
+            })
+        ;
+    }
+}
+
+pub fn clear_lines(
+    mut commands: Commands,
+    mut lock_events: EventReader<LockEvent>,
+    mut clear_notify: EventWriter<LineClearEvent>,
+    mut perfect_clear_notify: EventWriter<PerfectClearEvent>,
+    mut boards: Query<(&GridSize, &mut Heap, &mut GarbageRows)>,
+    mut blocks: Query<(Entity, &Parent, &mut GridPos)>,
+) {
+    for lock in lock_events.iter() {
+        let board = lock.board;
+        let Ok((grid_size, mut heap, mut garbage_rows)) =
+            boards.get_mut(board)
+        else {
+            continue;
+        };
+
+        let cleared = heap.clear_full_rows(grid_size.width);
+        garbage_rows.0 -= cleared
+            .iter()
+            .filter(|&&y| y < garbage_rows.0 as i16)
+            .count() as u32
+        ;
+
+        if !cleared.is_empty() {
+            for (entity, _, mut pos) in blocks
+                .iter_mut()
+                .filter(|(_, parent, _)| parent.get() == board)
+            {
+                if cleared.contains(&pos.y) {
+                    commands.entity(entity).despawn_recursive();
+                } else {
+                    let below = cleared.iter().filter(|&&y| y < pos.y).count();
+                    pos.y -= below as i16;
+                }
+            }
+        }
+
+        let lines = cleared.len() as u8;
+        clear_notify.send(LineClearEvent {
+            board,
+            lines,
+            t_spin: lock.t_spin,
+        });
+        if lines > 0 && heap.is_empty() {
+            perfect_clear_notify.send(PerfectClearEvent { board, lines });
+        }
+    }
+}
+
+// Push garbage into a board's heap, raising the heap's blocks along with it;
+// the piece in play is pushed up too if it would overlap
+pub fn add_garbage(
+    mut commands: Commands,
+    mut garbage_events: EventReader<GarbageEvent>,
+    mut top_out_notify: EventWriter<TopOutEvent>,
+    mut boards: Query<(
+        &GridSize,
+        &mut Heap,
+        &mut Origin,
+        &mut GarbageRows,
+    )>,
+    mut heap_blocks: Query<(&Parent, &mut GridPos), Without<Block>>,
+    mut piece_blocks: Query<(&Parent, &mut GridPos), With<Block>>,
 ) {
-    let grid_width = grid_size.width;
-
-    let (block_entities, block_pos): (Vec<_>, Vec<&GridPos>) =
-        tetromino.iter().unzip()
-    ;
-
-    if can_move(&block_pos, grid_width, MoveY::Down1, &heap) {
-        return;
-    }
-
-    if block_pos.iter().map(|pos| pos.y).any(|y| y >= grid_size.height) {
-        eprintln!("You lost");
-        lose_notify.send(AppExit);
-        return;
-    }
-
-    spawn_notify.send(SpawnEvent);
-
-    block_entities
-        .into_iter()
-        .for_each(|entity| {
-            commands.entity(entity).remove::<Block>();
-        })
-    ;
-    block_pos
-        .into_iter()
-        .for_each(|pos: &GridPos| {
-            let idx = pos.x + pos.y * grid_width;
-            // mark position in heap as occupied
-            heap.blocks[idx as usize] = HeapEntry::Occupied;
-        })
-    ;
+    for garbage in garbage_events.iter() {
+        let board = garbage.board;
+        let rows = garbage.holes.len() as i16;
+        let Ok((grid_size, mut heap, mut origin, mut garbage_rows)) =
+            boards.get_mut(board)
+        else {
+            continue;
+        };
+        let grid_width = grid_size.width;
+        if rows == 0 {
+            continue;
+        }
+
+        let top_out = TopOutEvent { board, top_out: TopOut::GarbageOut };
+        if heap.push_garbage(&garbage.holes, grid_width) {
+            top_out_notify.send(top_out);
+        }
+        heap_blocks
+            .iter_mut()
+            .filter(|(parent, _)| parent.get() == board)
+            .for_each(|(_, mut pos)| pos.y += rows)
+        ;
+        spawn_garbage(&mut commands, board, &garbage.holes, grid_width);
+        garbage_rows.0 += rows as u32;
+
+        let mut piece = piece_blocks
+            .iter_mut()
+            .filter(|(parent, _)| parent.get() == board)
+            .map(|(_, pos)| pos)
+            .collect::<Vec<_>>()
+        ;
+        while !piece.is_empty()
+            && !can_move(&piece, grid_width, MoveNeutral, &heap)
+        {
+            if piece.iter().any(|pos| pos.y >= grid_size.total_height()) {
+                top_out_notify.send(top_out);
+                break;
+            }
+            piece.iter_mut().for_each(|pos| pos.y += 1);
+            origin.pos.y += 1;
+        }
+    }
+}
+
+// Put the blocks of the given garbage rows, from the bottom up, on a board's
+// grid
+pub fn spawn_garbage(
+    commands: &mut Commands,
+    board: Entity,
+    holes: &[i16],
+    grid_width: i16,
+) {
+    for (y, &hole) in holes.iter().enumerate() {
+        for x in (0..grid_width).filter(|&x| x != hole) {
+            let pos = GridPos { x, y: y as i16 };
+            spawn_block(commands, board, pos, HEAP_COLOR);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use bevy::ecs::schedule::{Stage, SystemStage};
+    use super::*;
+
+
+    const SIZE: GridSize = GridSize { width: 4, height: 3, hidden_height: 1 };
+
+    fn occupied(heap: &Heap) -> Vec<(i16, i16)> {
+        (0..SIZE.total_height())
+            .flat_map(|y| (0..SIZE.width).map(move |x| (x, y)))
+            .filter(|&(x, y)| heap.occupied(GridPos { x, y }, SIZE.width))
+            .collect()
+    }
+
+    #[test]
+    fn garbage_rises_from_the_bottom_with_a_hole_in_each_row() {
+        let mut heap = Heap::new(SIZE);
+        heap.blocks[0] = HeapEntry::Occupied;
+        assert!(!heap.push_garbage(&[1, 3], SIZE.width));
+        assert_eq!(occupied(&heap), [
+            (0, 0), (2, 0), (3, 0),
+            (0, 1), (1, 1), (2, 1),
+            (0, 2),
+        ]);
+    }
+
+    #[test]
+    fn garbage_pushing_blocks_out_of_the_top_overflows() {
+        let mut heap = Heap::new(SIZE);
+        let top = SIZE.total_height() - 1;
+        heap.blocks[(top * SIZE.width) as usize] = HeapEntry::Occupied;
+        assert!(heap.push_garbage(&[0], SIZE.width));
+
+        // more rows than there's room for just fill the heap with garbage
+        let mut heap = Heap::new(SIZE);
+        assert!(!heap.push_garbage(&[0; 6], SIZE.width));
+        assert_eq!(occupied(&heap).len(), 3 * 4);
+    }
+
+    #[test]
+    fn full_rows_are_cleared_and_those_above_moved_down() {
+        let mut heap = Heap::new(SIZE);
+        heap.push_garbage(&[0, 1], SIZE.width);
+        heap.blocks.iter_mut().take(4).for_each(|entry| {
+            *entry = HeapEntry::Occupied;
+        });
+        assert_eq!(heap.clear_full_rows(SIZE.width), [0]);
+        assert_eq!(occupied(&heap), [(0, 0), (2, 0), (3, 0)]);
+        assert!(!heap.is_empty());
+    }
+
+    // Lock a piece onto a board with the given blocks in the heap (each one
+    // also a block entity), and return the clear events along with where
+    // the blocks that are left ended up
+    fn clear(blocks: &[(i16, i16)]) -> (Vec<(u8, bool)>, Vec<(i16, i16)>) {
+        let mut world = World::new();
+        world.init_resource::<Events<LockEvent>>();
+        world.init_resource::<Events<LineClearEvent>>();
+        world.init_resource::<Events<PerfectClearEvent>>();
+
+        let mut heap = Heap::new(SIZE);
+        for &(x, y) in blocks {
+            heap.blocks[(x + y * SIZE.width) as usize] = HeapEntry::Occupied;
+        }
+        let board = world.spawn((SIZE, heap, GarbageRows(0))).id();
+        for &(x, y) in blocks {
+            let block = world.spawn(GridPos { x, y }).id();
+            world.entity_mut(board).push_children(&[block]);
+        }
+        world.send_event(LockEvent {
+            board,
+            kind: PieceKind::I,
+            blocks: Vec::new(),
+            t_spin: None,
+        });
+        SystemStage::single(clear_lines).run(&mut world);
+
+        let perfect_clears = world
+            .resource::<Events<PerfectClearEvent>>()
+            .iter_current_update_events()
+            .map(|perfect_clear| perfect_clear.lines)
+            .collect::<Vec<_>>()
+        ;
+        let clears = world
+            .resource::<Events<LineClearEvent>>()
+            .iter_current_update_events()
+            .map(|clear| (clear.lines, perfect_clears.contains(&clear.lines)))
+            .collect()
+        ;
+        let mut left = world
+            .query::<&GridPos>()
+            .iter(&world)
+            .map(|pos| (pos.x, pos.y))
+            .collect::<Vec<_>>()
+        ;
+        left.sort_unstable();
+        (clears, left)
+    }
+
+    #[test]
+    fn clearing_every_block_is_a_perfect_clear() {
+        let row = (0..4).map(|x| (x, 0)).collect::<Vec<_>>();
+        assert_eq!(clear(&row), (vec![(1, true)], Vec::new()));
+
+        let mut blocks = row.clone();
+        blocks.push((2, 1));
+        assert_eq!(clear(&blocks), (vec![(1, false)], vec![(2, 0)]));
+        assert_eq!(clear(&[(2, 1)]), (vec![(0, false)], vec![(2, 1)]));
+    }
 }
@@ -0,0 +1,339 @@
+use bevy::prelude::*;
+use bevy::utils::Duration;
+use serde::{Deserialize, Serialize};
+use ::std::collections::BTreeMap;
+use ::std::error::Error;
+use ::std::fs;
+use ::std::path::Path;
+use ::std::time::{SystemTime, UNIX_EPOCH};
+use crate::game::{GameOverEvent, GameOverReason, GameSummary};
+use crate::mode::GameMode;
+use crate::replay::Recording;
+use crate::ruleset::Ruleset;
+
+
+// bump whenever older files could no longer be read in the same way
+const HIGH_SCORES_VERSION: u32 = 1;
+
+// relative to the working directory
+const HIGH_SCORES_PATH: &str = "high_scores.ron";
+
+// how many games each mode's table keeps
+const TABLE_SIZE: usize = 10;
+
+// The best games played in each mode, best first
+#[derive(Resource, Serialize, Deserialize)]
+pub struct HighScores {
+    version: u32,
+    // by mode name and whatever else sets games in it apart
+    tables: BTreeMap<String, Vec<HighScore>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HighScore {
+    pub score: u64,
+    pub lines: u32,
+    #[serde(default)]
+    pub pieces: u32,
+    pub time: Duration,
+    // seconds since the Unix epoch
+    pub date: u64,
+    pub seed: u64,
+}
+
+// where the last game placed in its mode's table, if anywhere; first place
+// makes for a personal best
+#[derive(Resource)]
+pub struct Ranking(pub Option<usize>);
+
+impl Default for HighScores {
+    fn default() -> Self {
+        Self { version: HIGH_SCORES_VERSION, tables: BTreeMap::new() }
+    }
+}
+
+impl HighScores {
+    // Read the high scores from disk; a file that can't be read is moved out
+    // of the way rather than overwritten, and play goes on with empty tables
+    pub fn load() -> Self {
+        let path = Path::new(HIGH_SCORES_PATH);
+        if !path.exists() {
+            return Self::default();
+        }
+
+        match Self::read(path) {
+            Ok(high_scores) => high_scores,
+            Err(err) => {
+                let backup = path.with_extension("ron.bak");
+                eprintln!(
+                    "Couldn't read high scores from {}: {err}; moving them to \
+                    {}",
+                    path.display(),
+                    backup.display(),
+                );
+                if let Err(err) = fs::rename(path, &backup) {
+                    eprintln!("Couldn't move high scores: {err}");
+                }
+                Self::default()
+            },
+        }
+    }
+
+    fn read(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let high_scores: Self = ron::from_str(&fs::read_to_string(path)?)?;
+        if high_scores.version != HIGH_SCORES_VERSION {
+            return Err(format!(
+                "high scores have version {}, but only version {} is supported",
+                high_scores.version,
+                HIGH_SCORES_VERSION,
+            ).into());
+        }
+        Ok(high_scores)
+    }
+
+    pub fn save(&self) {
+        let pretty = ron::ser::PrettyConfig::default();
+        // written in full before replacing the old file, so that quitting
+        // midway can't leave it half written
+        let temp_path = Path::new(HIGH_SCORES_PATH).with_extension("ron.tmp");
+        let result = ron::ser::to_string_pretty(self, pretty)
+            .map_err(Box::<dyn Error>::from)
+            .and_then(|ron| Ok(fs::write(&temp_path, ron)?))
+            .and_then(|()| Ok(fs::rename(&temp_path, HIGH_SCORES_PATH)?))
+        ;
+        if let Err(err) = result {
+            eprintln!("Couldn't save high scores: {err}");
+        }
+    }
+
+    pub fn table(&self, ruleset: &Ruleset) -> &[HighScore] {
+        self.tables
+            .get(&table_name(ruleset))
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+    }
+
+    // Enter a game into its mode's table, returning its place there if it
+    // made the cut
+    pub fn enter(
+        &mut self,
+        ruleset: &Ruleset,
+        entry: HighScore,
+    ) -> Option<usize> {
+        let table = self.tables.entry(table_name(ruleset)).or_default();
+        let rank = table
+            .iter()
+            .position(|other| better(ruleset.mode, &entry, other))
+            .unwrap_or(table.len())
+        ;
+        if rank >= TABLE_SIZE {
+            return None;
+        }
+
+        table.insert(rank, entry);
+        table.truncate(TABLE_SIZE);
+        Some(rank)
+    }
+}
+
+// The table a game goes in, e.g. "Marathon" or "Cheese, 18 rows 30% messy
+// from level 5"; games only compare to those played by the same settings
+fn table_name(ruleset: &Ruleset) -> String {
+    let mut name = ruleset.mode.to_string();
+    if let GameMode::Cheese { rows, messiness } = ruleset.mode {
+        name += &format!(", {rows} rows {messiness}% messy");
+    }
+    if ruleset.start_level != 1 {
+        name += &format!(" from level {}", ruleset.start_level);
+    }
+    name
+}
+
+// Whether one game beats another; sprints and cheese are a race, survival
+// is a matter of lasting, and the rest are scored
+fn better(mode: GameMode, entry: &HighScore, other: &HighScore) -> bool {
+    match mode {
+        GameMode::Sprint | GameMode::Cheese { .. } => entry.time < other.time,
+        GameMode::Survival => entry.time > other.time,
+        _ => entry.score > other.score,
+    }
+}
+
+// Whether a finished game can be ranked at all
+fn qualifies(mode: GameMode, summary: &GameSummary) -> bool {
+    match mode {
+        GameMode::Sprint | GameMode::Cheese { .. } => {
+            summary.reason == GameOverReason::Finished
+        },
+        // puzzles are solved or not, and each one is different, and versus
+        // is only ever against whoever else is playing
+        GameMode::Puzzle | GameMode::Versus => false,
+        _ => true,
+    }
+}
+
+
+pub fn record_high_score(
+    mut commands: Commands,
+    ruleset: Res<Ruleset>,
+    recording: Option<Res<Recording>>,
+    mut high_scores: ResMut<HighScores>,
+    mut game_over_events: EventReader<GameOverEvent>,
+) {
+    let Some(game_over) = game_over_events.iter().last() else { return };
+    let summary = &game_over.summary;
+    let mode = ruleset.mode;
+
+    // only games played live make it into the tables
+    let rank = recording
+        .filter(|_| qualifies(mode, summary))
+        .and_then(|recording| {
+            let date = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs()
+            ;
+            high_scores.enter(&ruleset, HighScore {
+                score: summary.score,
+                lines: summary.lines,
+                pieces: summary.pieces,
+                time: summary.time(),
+                date,
+                seed: recording.seed(),
+            })
+        })
+    ;
+    if rank.is_some() {
+        high_scores.save();
+    }
+    commands.insert_resource(Ranking(rank));
+}
+
+// e.g. "2022-10-17", in UTC
+pub fn format_date(date: u64) -> String {
+    // from days since the epoch to the civil calendar, as per
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let days = (date / 86_400) as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524
+        - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era
+        - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + (month <= 2) as i64;
+
+    format!("{year}-{month:02}-{day:02}")
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game::TopOut;
+
+
+    fn ruleset(mode: GameMode) -> Ruleset {
+        Ruleset { mode, ..Ruleset::default() }
+    }
+
+    fn entry(score: u64, seconds: u64) -> HighScore {
+        HighScore {
+            score,
+            lines: 0,
+            pieces: 0,
+            time: Duration::from_secs(seconds),
+            date: 0,
+            seed: 0,
+        }
+    }
+
+    #[test]
+    fn tables_keep_the_best_games_first() {
+        let mut high_scores = HighScores::default();
+        let ultra = ruleset(GameMode::Ultra);
+        assert_eq!(high_scores.enter(&ultra, entry(100, 0)), Some(0));
+        assert_eq!(high_scores.enter(&ultra, entry(300, 0)), Some(0));
+        assert_eq!(high_scores.enter(&ultra, entry(200, 0)), Some(1));
+        // ties go to whoever got there first
+        assert_eq!(high_scores.enter(&ultra, entry(200, 0)), Some(2));
+
+        let scores = high_scores
+            .table(&ultra)
+            .iter()
+            .map(|high_score| high_score.score)
+            .collect::<Vec<_>>()
+        ;
+        assert_eq!(scores, [300, 200, 200, 100]);
+        assert!(high_scores.table(&ruleset(GameMode::Zen)).is_empty());
+    }
+
+    #[test]
+    fn races_rank_by_time() {
+        let mut high_scores = HighScores::default();
+        let sprint = ruleset(GameMode::Sprint);
+        high_scores.enter(&sprint, entry(0, 60));
+        assert_eq!(high_scores.enter(&sprint, entry(0, 50)), Some(0));
+        // except survival, where lasting longer is better
+        let survival = ruleset(GameMode::Survival);
+        high_scores.enter(&survival, entry(0, 60));
+        assert_eq!(high_scores.enter(&survival, entry(0, 50)), Some(1));
+    }
+
+    #[test]
+    fn full_tables_turn_away_worse_games() {
+        let mut high_scores = HighScores::default();
+        let zen = ruleset(GameMode::Zen);
+        for score in 1..=TABLE_SIZE as u64 {
+            high_scores.enter(&zen, entry(score * 10, 0));
+        }
+        assert_eq!(high_scores.enter(&zen, entry(5, 0)), None);
+        assert_eq!(high_scores.enter(&zen, entry(15, 0)), Some(TABLE_SIZE - 1));
+        assert_eq!(high_scores.table(&zen).len(), TABLE_SIZE);
+        assert_eq!(high_scores.table(&zen).last().unwrap().score, 15);
+    }
+
+    #[test]
+    fn games_only_rank_against_the_same_settings() {
+        let mut high_scores = HighScores::default();
+        let cheese = |rows, messiness| {
+            ruleset(GameMode::Cheese { rows, messiness })
+        };
+        high_scores.enter(&cheese(10, 0), entry(0, 30));
+        assert!(high_scores.table(&cheese(18, 0)).is_empty());
+        assert!(high_scores.table(&cheese(10, 30)).is_empty());
+        assert_eq!(high_scores.table(&cheese(10, 0)).len(), 1);
+
+        let level_5 = Ruleset { start_level: 5, ..cheese(10, 0) };
+        assert!(high_scores.table(&level_5).is_empty());
+        assert_eq!(
+            table_name(&level_5),
+            "Cheese, 10 rows 0% messy from level 5",
+        );
+        assert_eq!(table_name(&ruleset(GameMode::Sprint)), "Sprint");
+    }
+
+    #[test]
+    fn races_only_count_once_finished() {
+        let summary = |reason| GameSummary {
+            reason,
+            ticks: 0,
+            score: 0,
+            lines: 0,
+            level: 1,
+            pieces: 0,
+        };
+        let finished = summary(GameOverReason::Finished);
+        let topped_out = summary(GameOverReason::TopOut(TopOut::BlockOut));
+        assert!(qualifies(GameMode::Sprint, &finished));
+        assert!(!qualifies(GameMode::Sprint, &topped_out));
+        assert!(qualifies(GameMode::Ultra, &topped_out));
+        assert!(!qualifies(GameMode::Versus, &finished));
+    }
+}
@@ -0,0 +1,225 @@
+use bevy::prelude::*;
+use bevy::sprite::Anchor;
+use bevy::utils::HashMap;
+use crate::board::Board;
+use crate::grid::GridSize;
+use crate::overlay::UiFont;
+use crate::finesse::{FinesseAlerts, FinesseFaultEvent};
+use crate::garbage::{GarbageRows, PendingGarbage};
+use crate::level::Level;
+use crate::mode::GameMode;
+use crate::movement::GarbageTimer;
+use crate::overlay::format_time;
+use crate::piece::{Hold, PieceKind, PieceQueue, Randomizer, coming};
+use crate::ruleset::Ruleset;
+use crate::score::{Score, ScoreEvent, ScoreKind};
+use crate::stats::Stats;
+use crate::tick::GameClock;
+use crate::BLOCK_SIZE;
+
+
+// the running tally beside a board's grid
+#[derive(Component)]
+pub struct Hud;
+
+// bar to the left of a board's grid, as tall as the garbage on its way
+#[derive(Component)]
+pub struct GarbageMeter;
+
+
+// Pieces by name, in order
+fn names(kinds: impl IntoIterator<Item = PieceKind>) -> String {
+    kinds
+        .into_iter()
+        .map(|kind| format!("{kind:?}"))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+pub fn spawn_hud(
+    mut commands: Commands,
+    font: Res<UiFont>,
+    boards: Query<(Entity, &GridSize), Added<Board>>,
+) {
+    for (board, grid_size) in boards.iter() {
+        commands.entity(board).with_children(|parent| {
+            parent.spawn((
+                Text2dBundle {
+                    text: Text::from_section("", TextStyle {
+                        font: font.0.clone(),
+                        font_size: 20.0,
+                        color: Color::WHITE,
+                    })
+                    .with_alignment(TextAlignment::CENTER_LEFT),
+                    transform: Transform::from_xyz(
+                        (grid_size.width as f32 * 0.5 + 1.0) * BLOCK_SIZE,
+                        0.0,
+                        1.0,
+                    ),
+                    ..Text2dBundle::default()
+                },
+                Hud,
+            ));
+        });
+    }
+}
+
+pub fn spawn_garbage_meter(
+    mut commands: Commands,
+    ruleset: Res<Ruleset>,
+    boards: Query<(Entity, &GridSize), Added<Board>>,
+) {
+    // only opponents send garbage
+    if ruleset.mode.players() < 2 {
+        return;
+    }
+
+    for (board, grid_size) in boards.iter() {
+        commands.entity(board).with_children(|parent| {
+            parent.spawn((
+                SpriteBundle {
+                    sprite: Sprite {
+                        custom_size: Some(Vec2::new(BLOCK_SIZE * 0.5, 0.0)),
+                        color: Color::rgb(0.9, 0.1, 0.1),
+                        anchor: Anchor::BottomCenter,
+                        ..Sprite::default()
+                    },
+                    transform: Transform::from_xyz(
+                        -(grid_size.width as f32 * 0.5 + 0.5) * BLOCK_SIZE,
+                        -(grid_size.height as f32 * 0.5) * BLOCK_SIZE,
+                        1.0,
+                    ),
+                    ..SpriteBundle::default()
+                },
+                GarbageMeter,
+            ));
+        });
+    }
+}
+
+pub fn update_garbage_meter(
+    boards: Query<(&GridSize, &PendingGarbage)>,
+    mut meters: Query<(&Parent, &mut Sprite), With<GarbageMeter>>,
+) {
+    for (parent, mut sprite) in meters.iter_mut() {
+        let Ok((grid_size, pending)) = boards.get(parent.get()) else {
+            continue;
+        };
+        // more than fits on the grid still tops out at the top of it
+        let lines = pending.lines().min(grid_size.height as u32);
+        sprite.custom_size = Some(
+            Vec2::new(BLOCK_SIZE * 0.5, lines as f32 * BLOCK_SIZE),
+        );
+    }
+}
+
+pub fn update_hud(
+    ruleset: Res<Ruleset>,
+    clock: Res<GameClock>,
+    finesse_alerts: Option<Res<FinesseAlerts>>,
+    mut score_events: EventReader<ScoreEvent>,
+    mut fault_events: EventReader<FinesseFaultEvent>,
+    // the latest noteworthy ways points were scored, by board
+    mut last_awards: Local<HashMap<Entity, String>>,
+    boards: Query<(
+        Entity,
+        &Board,
+        &Score,
+        &Level,
+        &Stats,
+        &GarbageRows,
+        &GarbageTimer,
+        (&Randomizer, &Hold, Option<&PieceQueue>),
+    )>,
+    mut hud: Query<(&Parent, &mut Text), With<Hud>>,
+) {
+    // boards of games gone by
+    last_awards.retain(|&board, _| boards.contains(board));
+
+    // a clear can score several ways at once, e.g. a tetris and a combo
+    let mut awards = HashMap::<Entity, Vec<String>>::new();
+    for event in score_events.iter().filter(|event| !matches!(
+        event.kind,
+        ScoreKind::SoftDrop { .. } | ScoreKind::HardDrop { .. },
+    )) {
+        awards
+            .entry(event.board)
+            .or_default()
+            .push(format!("{}\n+{}", event.kind, event.points))
+        ;
+    }
+    for (board, awards) in awards {
+        last_awards.insert(board, awards.join("\n"));
+    }
+    for fault in fault_events.iter().filter(|_| finesse_alerts.is_some()) {
+        last_awards.insert(fault.board, format!(
+            "Finesse fault\n{} inputs,\n{} needed",
+            fault.used,
+            fault.needed,
+        ));
+    }
+
+    let mode = ruleset.mode;
+    for (parent, mut text) in hud.iter_mut() {
+        let Ok((
+            board,
+            &Board { player },
+            score,
+            level,
+            stats,
+            garbage_rows,
+            garbage_timer,
+            (randomizer, hold, queue),
+        )) = boards.get(parent.get()) else {
+            continue;
+        };
+
+        let heading = match mode.players() {
+            1 => mode.to_string(),
+            _ => format!("Player {}", player + 1),
+        };
+        let lines = match (mode, mode.line_goal()) {
+            (GameMode::Cheese { .. }, _) => {
+                format!("{}\n\nGarbage\n{}", score.lines, garbage_rows.0)
+            },
+            (GameMode::Survival, _) => format!(
+                "{}\n\nNext row\n{:.1}s",
+                score.lines,
+                garbage_timer.remaining_secs(),
+            ),
+            (_, Some(goal)) => format!("{}/{goal}", score.lines),
+            (_, None) => score.lines.to_string(),
+        };
+        // counting down if time is what's running out
+        let time = match mode.time_limit() {
+            Some(limit) => limit.saturating_sub(clock.time()),
+            None => clock.time(),
+        };
+
+        let hold = hold.piece.map_or(String::from("-"), |kind| {
+            format!("{kind:?}")
+        });
+        // a fixed queue is no secret
+        let coming = match queue {
+            Some(queue) => format!("Queue\n{}", names(queue.0.iter().copied())),
+            None => {
+                let preview = coming(randomizer, None, ruleset.preview);
+                format!("Next\n{}", names(preview))
+            },
+        };
+
+        text.sections[0].value = format!(
+            "{heading}\n\nScore\n{}\n\nLines\n{lines}\n\nLevel\n{}\n\n\
+            Time\n{}\n\nPPS {:.2}\nKPP {:.2}\nAPM {:.1}\nFaults {}\n\n\
+            Hold\n{hold}\n\n{coming}\n\n{}",
+            score.points,
+            level.0,
+            format_time(time),
+            stats.pieces_per_second(&clock),
+            stats.keys_per_piece(),
+            stats.attack_per_minute(&clock),
+            stats.finesse_faults,
+            last_awards.get(&board).map_or("", String::as_str),
+        );
+    }
+}
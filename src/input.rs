@@ -1,91 +1,486 @@
 use bevy::prelude::*;
 use bevy::input::keyboard::KeyboardInput;
+use bevy::utils::{Duration, HashMap, HashSet};
+use ::core::str::FromStr;
+use ::std::mem;
+use serde::{Deserialize, Serialize};
+use crate::board::Board;
+use crate::game::GameState;
+use crate::net::NetSession;
+use crate::ruleset::Ruleset;
+use crate::tick::{GameClock, TICK};
+use crate::replay::{InputChange, Playback, Recording};
 
 
-#[repr(u16)]
-pub enum Input {
-    LeftPressed = 0,
-    RightPressed = 1,
-    SoftDropPressed = 2,
-    ClkwPressed = 3,
-    ClkwJustPressed = 4,
-    CclwPressed = 5,
-    CclwJustPressed = 6,
-    HardDropPressed = 7,
-    HardDropJustPressed = 8,
+// Everything the player can ask of the game; gameplay systems query these
+// rather than keys, which are mapped onto them by `KeyBindings`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Action {
+    Left,
+    Right,
+    SoftDrop,
+    SonicDrop,
+    HardDrop,
+    RotateClockwise,
+    RotateCounterclockwise,
+    Rotate180,
+    Hold,
+    Pause,
+    Restart,
+    Confirm,
+    Menu,
 }
 
-impl Input {
-    fn to_bitmask(self) -> u16 {
-        0b1 << self as u16
+impl Action {
+    pub const ALL: [Action; 13] = [
+        Action::Left,
+        Action::Right,
+        Action::SoftDrop,
+        Action::SonicDrop,
+        Action::HardDrop,
+        Action::RotateClockwise,
+        Action::RotateCounterclockwise,
+        Action::Rotate180,
+        Action::Hold,
+        Action::Pause,
+        Action::Restart,
+        Action::Confirm,
+        Action::Menu,
+    ];
+    pub const COUNT: usize = Self::ALL.len();
+
+    // whether the action is about the game as a whole rather than gameplay
+    pub fn is_menu(self) -> bool {
+        matches!(
+            self,
+            Action::Pause | Action::Restart | Action::Confirm | Action::Menu
+        )
+    }
+
+    fn idx(self) -> usize {
+        self as usize
     }
 }
 
-#[derive(Resource)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ActionState {
+    pressed: bool,
+    just_pressed: bool,
+    just_released: bool,
+    // how long the action has been pressed (zero if it isn't)
+    held: Duration,
+    // orders presses of different actions, even within the same update
+    press_seq: u64,
+}
+
+impl ActionState {
+    fn press(&mut self, press_seq: u64) {
+        self.pressed = true;
+        self.just_pressed = true;
+        self.held = Duration::ZERO;
+        self.press_seq = press_seq;
+    }
+
+    fn release(&mut self) {
+        self.pressed = false;
+        self.just_released = true;
+        self.held = Duration::ZERO;
+    }
+
+    // forget the edges of the last update and age the press
+    fn tick(&mut self, delta: Duration) {
+        self.just_pressed = false;
+        self.just_released = false;
+        if self.pressed {
+            self.held += delta;
+        }
+    }
+}
+
+// How to settle two opposing actions (SOCD: simultaneous opposing cardinal
+// directions) that are active at the same time
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum SocdMode {
+    // the actions cancel each other out
+    #[default]
+    Neutral,
+    // the most recently pressed action wins
+    LastWins,
+    // the action that was pressed first wins
+    FirstWins,
+}
+
+impl FromStr for SocdMode {
+    type Err = String;
+
+    fn from_str(mode: &str) -> Result<Self, Self::Err> {
+        match mode {
+            "neutral" => Ok(Self::Neutral),
+            "last-wins" => Ok(Self::LastWins),
+            "first-wins" => Ok(Self::FirstWins),
+            _ => Err(format!(
+                "unknown SOCD mode '{mode}' (expected 'neutral', 'last-wins' \
+                or 'first-wins')"
+            )),
+        }
+    }
+}
+
+// the actions of the player a board belongs to
+#[derive(Clone, Component)]
 pub struct Inputs {
-    bitflags: u16,
+    states: [ActionState; Action::COUNT],
+    presses: u64,
 }
 
 impl Inputs {
     pub fn new() -> Self {
-        Self { bitflags: 0 }
+        Self {
+            states: [ActionState::default(); Action::COUNT],
+            presses: 0,
+        }
+    }
+
+    pub fn pressed(&self, action: Action) -> bool {
+        self.states[action.idx()].pressed
+    }
+
+    // pressed since the last update (even if already released again)
+    pub fn just_pressed(&self, action: Action) -> bool {
+        self.states[action.idx()].just_pressed
+    }
+
+    // released since the last update (even if already pressed again)
+    #[allow(dead_code)]
+    pub fn just_released(&self, action: Action) -> bool {
+        self.states[action.idx()].just_released
+    }
+
+    pub fn held_duration(&self, action: Action) -> Duration {
+        self.states[action.idx()].held
+    }
+
+    // Pick between two opposing actions, `active` being whichever query
+    // (e.g. `Inputs::pressed`) makes an action count
+    pub fn resolve_socd(
+        &self,
+        mode: SocdMode,
+        active: impl Fn(&Self, Action) -> bool,
+        first: Action,
+        second: Action,
+    ) -> Option<Action> {
+        match (active(self, first), active(self, second)) {
+            (false, false) => None,
+            (true, false) => Some(first),
+            (false, true) => Some(second),
+            (true, true) => {
+                let first_seq = self.states[first.idx()].press_seq;
+                let second_seq = self.states[second.idx()].press_seq;
+                match mode {
+                    SocdMode::Neutral => None,
+                    SocdMode::LastWins if first_seq > second_seq => Some(first),
+                    SocdMode::LastWins => Some(second),
+                    SocdMode::FirstWins if first_seq < second_seq => {
+                        Some(first)
+                    },
+                    SocdMode::FirstWins => Some(second),
+                }
+            },
+        }
     }
 
-    fn set_action_state(&mut self, input: Input, signalled: bool) {
-        if signalled {
-            // set the bit at the appropriate location
-            self.bitflags |=  input.to_bitmask();
+    // returns whether the state of the action actually changed
+    fn set_action_state(&mut self, action: Action, pressed: bool) -> bool {
+        let state = &mut self.states[action.idx()];
+        if state.pressed == pressed {
+            return false;
+        }
+
+        if pressed {
+            self.presses += 1;
+            state.press(self.presses);
         } else {
-            // reset the bit at the appropriate location
-            self.bitflags &= !input.to_bitmask();
+            state.release();
         }
+        true
     }
 
-    pub fn get_action_state(&self, input: Input) -> bool {
-        self.bitflags & input.to_bitmask() != 0
+    fn tick(&mut self, delta: Duration) {
+        self.states.iter_mut().for_each(|state| state.tick(delta));
     }
 }
 
+// Actions as seen from outside of the game (e.g. pausing it), which have to
+// keep working whether or not ticks are being run; any player's keys count
+#[derive(Resource, Deref)]
+pub struct MenuInputs(Inputs);
 
-pub fn input(
-    mut inputs: ResMut<Inputs>,
+impl MenuInputs {
+    pub fn new() -> Self {
+        Self(Inputs::new())
+    }
+}
+
+// action changes from the keyboard, by player, waiting for the next tick
+#[derive(Resource, Default)]
+pub struct InputQueue(pub Vec<(usize, Action, bool)>);
+
+#[derive(Resource)]
+pub struct KeyBindings {
+    // playing alone, with a choice of keys for most actions
+    solo: HashMap<KeyCode, Action>,
+    // two players sharing the keyboard, each with a side of it; menu actions
+    // are bound to the first player, but belong to neither
+    versus: HashMap<KeyCode, (usize, Action)>,
+}
+
+impl KeyBindings {
+    // the player, and their action, that a key is bound to given how many
+    // players there are
+    pub fn get(
+        &self,
+        key_code: KeyCode,
+        players: usize,
+    ) -> Option<(usize, Action)> {
+        if players > 1 {
+            self.versus.get(&key_code).copied()
+        } else {
+            self.solo.get(&key_code).map(|&action| (0, action))
+        }
+    }
+}
+
+impl Default for KeyBindings {
+    fn default() -> Self {
+        use self::Action as A;
+        use KeyCode::*;
+
+
+        let solo = [
+            (W, A::HardDrop), (I, A::HardDrop), (Up, A::HardDrop),
+            (KeyCode::A, A::Left), (J, A::Left), (Left, A::Left),
+            (S, A::SoftDrop), (K, A::SoftDrop), (Down, A::SoftDrop),
+            (D, A::Right), (L, A::Right), (Right, A::Right),
+            (Space, A::SonicDrop),
+            (Z, A::RotateCounterclockwise),
+            (X, A::RotateClockwise),
+            (V, A::Rotate180),
+            (C, A::Hold), (LShift, A::Hold),
+            (Escape, A::Pause), (P, A::Pause),
+            (R, A::Restart),
+            (Return, A::Confirm),
+            (M, A::Menu), (Back, A::Menu),
+        ];
+        let versus = [
+            (W, (0, A::HardDrop)),
+            (KeyCode::A, (0, A::Left)),
+            (S, (0, A::SoftDrop)),
+            (D, (0, A::Right)),
+            (LControl, (0, A::SonicDrop)),
+            (Q, (0, A::RotateCounterclockwise)),
+            (E, (0, A::RotateClockwise)),
+            (F, (0, A::Rotate180)),
+            (LShift, (0, A::Hold)),
+            (Up, (1, A::HardDrop)),
+            (Left, (1, A::Left)),
+            (Down, (1, A::SoftDrop)),
+            (Right, (1, A::Right)),
+            (RControl, (1, A::SonicDrop)),
+            (Comma, (1, A::RotateCounterclockwise)),
+            (Period, (1, A::RotateClockwise)),
+            (Slash, (1, A::Rotate180)),
+            (RShift, (1, A::Hold)),
+            (Escape, (0, A::Pause)), (P, (0, A::Pause)),
+            (R, (0, A::Restart)),
+            (Return, (0, A::Confirm)),
+            (M, (0, A::Menu)), (Back, (0, A::Menu)),
+        ];
+        Self {
+            solo: solo.into_iter().collect(),
+            versus: versus.into_iter().collect(),
+        }
+    }
+}
+
+
+// Translate keyboard events into action changes as they come in, whether or
+// not a tick is run this frame
+pub fn read_keyboard(
+    time: Res<Time>,
+    ruleset: Res<Ruleset>,
+    bindings: Res<KeyBindings>,
+    session: Option<Res<NetSession>>,
+    game_state: Res<State<GameState>>,
+    mut menu_inputs: ResMut<MenuInputs>,
+    mut queue: ResMut<InputQueue>,
+    mut held_keys: Local<HashSet<KeyCode>>,
     mut input_events: EventReader<KeyboardInput>,
 ) {
-    use self::Input::*;
-    use KeyCode::*;
     use bevy::input::ButtonState;
 
 
-    // used to determine state of just pressed action later
-    let prev_hrddrp_pressed = inputs.get_action_state(HardDropPressed);
-    let prev_clkw_pressed = inputs.get_action_state(ClkwPressed);
-    let prev_cclw_pressed = inputs.get_action_state(CclwPressed);
+    menu_inputs.0.tick(time.delta());
 
+    // over the network, the player on this end plays with the keys one
+    // would play alone with
+    let players = match session {
+        Some(_) => 1,
+        None => ruleset.mode.players(),
+    };
+    let bound = |key_code: &KeyCode| {
+        bindings
+            .get(*key_code, players)
+            .map(|(player, action)| match &session {
+                Some(session) => (session.player, action),
+                None => (player, action),
+            })
+    };
     for (state, key_code) in input_events
         .iter()
         .map(|key|
             (key.state, key.key_code.expect("Key not in keyboard map (?)"))
         )
     {
-        let action = match key_code {
-            W | I | Up    => HardDropPressed,
-            A | J | Left  => LeftPressed,
-            S | K | Down  => SoftDropPressed,
-            D | L | Right => RightPressed,
-            Z             => CclwPressed,
-            X             => ClkwPressed,
-            _             => continue,
+        let Some((player, action)) = bound(&key_code) else {
+            continue;
+        };
+
+        match state {
+            ButtonState::Pressed => held_keys.insert(key_code),
+            ButtonState::Released => held_keys.remove(&key_code),
         };
-        inputs.set_action_state(action, state == ButtonState::Pressed);
+        // several keys may be bound to the same action, which stays pressed
+        // for as long as any of them is held
+        // (whichever player's keys they are, as far as the menu goes)
+        let pressed = held_keys
+            .iter()
+            .any(|key_code| bound(key_code).is_some_and(|(_, bound)| {
+                bound == action
+            }))
+        ;
+        menu_inputs.0.set_action_state(action, pressed);
+        if !action.is_menu() {
+            let pressed = held_keys
+                .iter()
+                .any(|key_code| bound(key_code) == Some((player, action)))
+            ;
+            // a press while the game is paused (or over) would otherwise go
+            // off as soon as it's resumed; letting go still counts
+            if pressed && *game_state.current() != GameState::Playing {
+                continue;
+            }
+            queue.0.push((player, action, pressed));
+        }
     }
+}
+
+// Apply this tick's action changes, taken from the replay being played back
+// if there is one and from the keyboard otherwise
+pub fn input(
+    clock: Res<GameClock>,
+    mut queue: ResMut<InputQueue>,
+    playback: Option<ResMut<Playback>>,
+    recording: Option<ResMut<Recording>>,
+    session: Option<Res<NetSession>>,
+    mut boards: Query<(&Board, &mut Inputs)>,
+) {
+    boards.for_each_mut(|(_, mut inputs)| inputs.tick(TICK));
 
-    let mut set_just_pressed = |prev_pressed: bool, p_action, jp_action| {
-        let curr_pressed = inputs.get_action_state(p_action);
-        let just_pressed = !prev_pressed && curr_pressed;
-        inputs.set_action_state(jp_action, just_pressed);
+    let changes = match playback {
+        Some(mut playback) => playback.take(clock.tick),
+        None => mem::take(&mut queue.0),
     };
+    // live input is only ignored, not kept for later, during playback
+    queue.0.clear();
 
-    set_just_pressed(prev_hrddrp_pressed, HardDropPressed, HardDropJustPressed);
-    set_just_pressed(prev_clkw_pressed, ClkwPressed, ClkwJustPressed);
-    set_just_pressed(prev_cclw_pressed, CclwPressed, CclwJustPressed);
+    // over the network, actions only go on record once they're certain
+    let mut recording = recording.filter(|_| session.is_none());
+    for (player, action, pressed) in changes {
+        let Some((_, mut inputs)) = boards
+            .iter_mut()
+            .find(|(board, _)| board.player == player)
+        else {
+            continue;
+        };
+        if !inputs.set_action_state(action, pressed) {
+            continue;
+        }
+        if let Some(recording) = &mut recording {
+            recording.push(InputChange {
+                tick: clock.tick,
+                player,
+                action,
+                pressed,
+            });
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+
+    fn socd(inputs: &Inputs, mode: SocdMode) -> Option<Action> {
+        inputs.resolve_socd(mode, Inputs::pressed, Action::Left, Action::Right)
+    }
+
+    #[test]
+    fn opposing_actions_cancel_out_when_neutral() {
+        let mut inputs = Inputs::new();
+        inputs.set_action_state(Action::Left, true);
+        assert_eq!(socd(&inputs, SocdMode::Neutral), Some(Action::Left));
+        inputs.set_action_state(Action::Right, true);
+        assert_eq!(socd(&inputs, SocdMode::Neutral), None);
+        inputs.set_action_state(Action::Left, false);
+        assert_eq!(socd(&inputs, SocdMode::Neutral), Some(Action::Right));
+    }
+
+    #[test]
+    fn the_later_press_wins_and_gives_way_once_released() {
+        let mut inputs = Inputs::new();
+        inputs.set_action_state(Action::Right, true);
+        inputs.set_action_state(Action::Left, true);
+        assert_eq!(socd(&inputs, SocdMode::LastWins), Some(Action::Left));
+
+        // pressing again makes for a later press
+        inputs.set_action_state(Action::Right, false);
+        inputs.set_action_state(Action::Right, true);
+        assert_eq!(socd(&inputs, SocdMode::LastWins), Some(Action::Right));
+        inputs.set_action_state(Action::Right, false);
+        assert_eq!(socd(&inputs, SocdMode::LastWins), Some(Action::Left));
+    }
+
+    #[test]
+    fn the_earlier_press_wins_until_released() {
+        let mut inputs = Inputs::new();
+        inputs.set_action_state(Action::Right, true);
+        inputs.set_action_state(Action::Left, true);
+        assert_eq!(socd(&inputs, SocdMode::FirstWins), Some(Action::Right));
+
+        inputs.set_action_state(Action::Right, false);
+        assert_eq!(socd(&inputs, SocdMode::FirstWins), Some(Action::Left));
+        inputs.set_action_state(Action::Right, true);
+        assert_eq!(socd(&inputs, SocdMode::FirstWins), Some(Action::Left));
+    }
+
+    #[test]
+    fn edges_last_for_one_update() {
+        let mut inputs = Inputs::new();
+        inputs.set_action_state(Action::HardDrop, true);
+        assert!(inputs.just_pressed(Action::HardDrop));
+        inputs.tick(Duration::from_millis(10));
+        assert!(!inputs.just_pressed(Action::HardDrop));
+        assert_eq!(
+            inputs.held_duration(Action::HardDrop),
+            Duration::from_millis(10),
+        );
+
+        inputs.set_action_state(Action::HardDrop, false);
+        assert!(inputs.just_released(Action::HardDrop));
+        inputs.tick(Duration::from_millis(10));
+        assert!(!inputs.just_released(Action::HardDrop));
+        assert!(inputs.held_duration(Action::HardDrop).is_zero());
+    }
 }
@@ -0,0 +1,124 @@
+use bevy::prelude::*;
+use bevy::utils::Duration;
+use serde::{Deserialize, Serialize};
+use crate::movement::GravityTimer;
+use crate::ruleset::Ruleset;
+use crate::score::Score;
+
+
+// The current level, which speeds up gravity and multiplies points
+#[derive(Clone, Component)]
+pub struct Level(pub u32);
+
+// How many lines it takes to get from one level to the next
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum LevelGoal {
+    // the same number of lines for every level
+    Fixed(u32),
+    // five times the level being left, as per the guideline's variable goal
+    Variable,
+}
+
+impl LevelGoal {
+    // level reached after clearing the given number of lines
+    pub fn level(&self, start_level: u32, lines: u32) -> u32 {
+        match *self {
+            Self::Fixed(goal) => start_level + lines / goal.max(1),
+            Self::Variable => {
+                let mut level = start_level.max(1);
+                let mut lines_left = lines;
+                while lines_left >= 5 * level {
+                    lines_left -= 5 * level;
+                    level += 1;
+                }
+                level
+            },
+        }
+    }
+}
+
+// Time it takes gravity to move the piece down by one row, by level
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum GravityCurve {
+    // the same speed at every level
+    Constant(Duration),
+    // (0.8 - (level - 1) * 0.007) ^ (level - 1) seconds, as per the
+    // guideline; it gets no faster past level 20
+    Guideline,
+}
+
+impl GravityCurve {
+    pub fn row_time(&self, level: u32) -> Duration {
+        match *self {
+            Self::Constant(row_time) => row_time,
+            Self::Guideline => {
+                let level = level.clamp(1, 20) as i32 - 1;
+                let seconds = (0.8 - level as f64 * 0.007).powi(level);
+                Duration::from_secs_f64(seconds)
+            },
+        }
+        // the timer can't repeat on a zero duration
+        .max(Duration::from_micros(1))
+    }
+}
+
+
+// Go up a level once enough lines have been cleared
+pub fn level_up(
+    ruleset: Res<Ruleset>,
+    mut boards: Query<(&Score, &mut Level, &mut GravityTimer)>,
+) {
+    if !ruleset.mode.levels_up() {
+        return;
+    }
+
+    for (score, mut level, mut gravity_timer) in boards.iter_mut() {
+        let reached =
+            ruleset.level_goal.level(ruleset.start_level, score.lines)
+        ;
+        if reached == level.0 {
+            continue;
+        }
+
+        level.0 = reached;
+        gravity_timer.set_duration(ruleset.gravity.row_time(reached));
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+
+    #[test]
+    fn fixed_goals_take_the_same_lines_every_level() {
+        let goal = LevelGoal::Fixed(10);
+        assert_eq!(goal.level(1, 9), 1);
+        assert_eq!(goal.level(1, 10), 2);
+        assert_eq!(goal.level(5, 25), 7);
+        // a goal of nothing is taken as one line
+        assert_eq!(LevelGoal::Fixed(0).level(1, 3), 4);
+    }
+
+    #[test]
+    fn variable_goals_grow_with_the_level() {
+        let goal = LevelGoal::Variable;
+        assert_eq!(goal.level(1, 4), 1);
+        assert_eq!(goal.level(1, 5), 2);
+        assert_eq!(goal.level(1, 14), 2);
+        assert_eq!(goal.level(1, 15), 3);
+        assert_eq!(goal.level(1, 30), 4);
+        assert_eq!(goal.level(3, 14), 3);
+        assert_eq!(goal.level(3, 15), 4);
+    }
+
+    #[test]
+    fn guideline_gravity_stops_speeding_up_past_level_20() {
+        let gravity = GravityCurve::Guideline;
+        assert_eq!(gravity.row_time(1), Duration::from_secs(1));
+        assert!(gravity.row_time(2) < gravity.row_time(1));
+        assert_eq!(gravity.row_time(30), gravity.row_time(20));
+        assert!(!GravityCurve::Constant(Duration::ZERO).row_time(1).is_zero());
+    }
+}
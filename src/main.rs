@@ -1,110 +1,457 @@
+// bevy systems routinely take more parameters, and query for more at once,
+// than clippy would like
+#![allow(clippy::too_many_arguments, clippy::type_complexity)]
+
+mod board;
 mod movement;
 mod grid;
 mod piece;
 mod rotation;
 mod heap;
 mod input;
+mod tick;
+mod ruleset;
+mod replay;
+mod game;
+mod overlay;
+mod score;
+mod level;
+mod mode;
+mod attack;
+mod stats;
+mod finesse;
+mod placement;
+mod high_scores;
+mod puzzle;
+mod garbage;
+mod hud;
+mod net;
+mod rollback;
+mod bot;
 
 use bevy::prelude::*;
-use movement::{
-    GravityTimer,
-    MovementXTimer,
-    MovementYTimer,
-    movement,
-};
+use bevy::utils::{Duration, HashMap};
+use board::Board;
+use movement::{DropEvent, movement};
 use rotation::rotation;
 use grid::{GridSize, GridPos};
-use piece::{SpawnEvent, Origin, OriginMode, spawn};
-use heap::{HeapEntry, Heap, lock};
-use input::{Inputs, input};
+use piece::{OutOfPiecesEvent, SpawnEvent, hold, spawn};
+use heap::{
+    LockEvent,
+    LineClearEvent,
+    PerfectClearEvent,
+    GarbageEvent,
+    lock,
+    clear_lines,
+    add_garbage,
+};
+use input::{
+    InputQueue,
+    KeyBindings,
+    MenuInputs,
+    SocdMode,
+    input,
+    read_keyboard,
+};
+use tick::{GameTick, BeginTick, GameClock, TickApp, run_ticks};
+use ruleset::Ruleset;
+use replay::{Replay, finish_replay, save_replay};
+use game::{
+    GameSource,
+    GameState,
+    GameOverEvent,
+    TopOutEvent,
+    start_game,
+    end_game,
+    game_over,
+    start_from_menu,
+    pause,
+    restart,
+    back_to_menu,
+};
+use overlay::{load_font, show_overlay};
+use score::{ScoreEvent, score};
+use level::level_up;
+use mode::{GameMode, GoalEvent, check_goal, select_mode};
+use puzzle::Puzzle;
+use garbage::{land_garbage, rise_garbage, send_garbage};
+use stats::update_stats;
+use finesse::{FinesseAlerts, FinesseFaultEvent, finesse};
+use high_scores::{HighScores, record_high_score};
+use hud::{
+    spawn_garbage_meter,
+    spawn_hud,
+    update_garbage_meter,
+    update_hud,
+};
+use attack::AttackTable;
+use net::{
+    NetConditions,
+    NetSession,
+    check_sync,
+    end_session,
+    exchange_inputs,
+    receive_messages,
+    watch_session,
+};
+use rollback::{Snapshots, roll_back, save_snapshot};
+use bot::{BotWeights, Bots, MAX_LOOKAHEAD, drive_bots};
+use ::std::env;
+use ::std::path::Path;
 
 
 // pixel (?) width of a block
 const BLOCK_SIZE: f32 = 25.0;
 
+// rows of the hidden buffer, right above the visible field, that are shown
+// anyway so that pieces can be seen as they spawn
+const SPAWN_AREA_HEIGHT: i16 = 3;
+
 
 fn main() {
-    App::new()
+    let mut app = App::new();
+
+    // e.g. `quad --join 192.168.0.2:7878`, against `quad --host 7878`
+    if let Some(address) = arg_value("--join") {
+        let (session, seed, ruleset) = NetSession::join(&address)
+            .unwrap_or_else(|err| {
+                panic!("Couldn't join the game at {address}: {err}")
+            })
+        ;
+        app
+            .insert_resource(ruleset)
+            .insert_resource(GameSource::Live { seed: Some(seed) })
+            .insert_resource(session)
+        ;
+    // e.g. `quad --replay replays/1666000000000-00000000deadbeef.ron`
+    } else if let Some(path) = arg_value("--replay") {
+        let replay = Replay::load(Path::new(&path)).unwrap_or_else(|err| {
+            panic!("Couldn't load replay from {path}: {err}")
+        });
+        app
+            .insert_resource(replay.ruleset.clone())
+            .insert_resource(GameSource::Replay(Box::new(replay)))
+        ;
+    } else {
+        // e.g. `quad --mode sprint --socd last-wins --seed 42 --level 5`
+        let mode = arg_value("--mode")
+            .map(|mode| {
+                mode.parse::<GameMode>().unwrap_or_else(|err| panic!("{err}"))
+            })
+            .unwrap_or_default()
+        ;
+        let socd_mode = arg_value("--socd")
+            .map(|mode| {
+                mode.parse::<SocdMode>().unwrap_or_else(|err| panic!("{err}"))
+            })
+            .unwrap_or_default()
+        ;
+        let seed = arg_value("--seed")
+            .map(|seed| seed.parse().expect("Seed must be a number"))
+        ;
+        let start_level = arg_value("--level")
+            .map(|level| level.parse().expect("Level must be a number"))
+            .unwrap_or(1)
+        ;
+        // e.g. `quad --mode cheese --garbage-rows 18 --messiness 30`
+        let mode = match mode {
+            GameMode::Cheese { rows, messiness } => GameMode::Cheese {
+                rows: arg_value("--garbage-rows")
+                    .map(|rows| rows.parse().expect("Rows must be a number"))
+                    .unwrap_or(rows),
+                messiness: arg_value("--messiness")
+                    .map(|messiness| {
+                        messiness
+                            .parse::<u8>()
+                            .expect("Messiness must be a number")
+                            .min(100)
+                    })
+                    .unwrap_or(messiness),
+            },
+            mode => mode,
+        };
+        let mut ruleset = Ruleset {
+            mode,
+            socd_mode,
+            start_level,
+            ..Ruleset::default()
+        };
+        if let GameMode::Cheese { rows, .. } = ruleset.mode {
+            // the piece needs somewhere to go
+            if rows >= ruleset.grid_size.height as u32 {
+                panic!("Garbage rows must be fewer than the field is tall");
+            }
+        }
+
+        // e.g. `quad --mode versus --attack-table tables/classic.ron
+        // --garbage-delay 1000`
+        if let Some(path) = arg_value("--attack-table") {
+            ruleset.attack = AttackTable::load(Path::new(&path))
+                .unwrap_or_else(|err| {
+                    panic!("Couldn't load attack table from {path}: {err}")
+                })
+            ;
+        }
+        if let Some(delay) = arg_value("--garbage-delay") {
+            let millis = delay.parse().expect("Delay must be a number");
+            ruleset.garbage_delay = Duration::from_millis(millis);
+        }
+
+        // e.g. `quad --puzzle puzzles/tsd.ron`
+        if let Some(path) = arg_value("--puzzle") {
+            let puzzle = Puzzle::load(Path::new(&path)).unwrap_or_else(|err| {
+                panic!("Couldn't load puzzle from {path}: {err}")
+            });
+            if puzzle.board.len() > ruleset.grid_size.height as usize {
+                panic!("Puzzle board is taller than the field");
+            }
+            ruleset.mode = GameMode::Puzzle;
+            ruleset.grid_size.width = puzzle.width();
+            ruleset.puzzle = Some(puzzle);
+        }
+
+        // e.g. `quad --host 7878 --seed 42`, which is always versus
+        if let Some(port) = arg_value("--host") {
+            let port = port.parse().expect("Port must be a number");
+            let seed = seed.unwrap_or_else(rand::random);
+            ruleset.mode = GameMode::Versus;
+            let session = NetSession::host(port, seed, &ruleset)
+                .unwrap_or_else(|err| panic!("Couldn't host a game: {err}"))
+            ;
+            app
+                .insert_resource(ruleset)
+                .insert_resource(GameSource::Live { seed: Some(seed) })
+                .insert_resource(session)
+            ;
+        } else {
+            app
+                .insert_resource(ruleset)
+                .insert_resource(GameSource::Live { seed })
+            ;
+        }
+    }
+
+    // e.g. `quad --host 7878 --latency 80 --packet-loss 2`
+    let latency = arg_value("--latency")
+        .map(|latency| latency.parse().expect("Latency must be a number"))
+    ;
+    let packet_loss = arg_value("--packet-loss")
+        .map(|loss| loss.parse::<u8>().expect("Loss must be a number").min(100))
+    ;
+    if let Some(mut session) = app.world.get_resource_mut::<NetSession>() {
+        if latency.is_some() || packet_loss.is_some() {
+            session.simulate(NetConditions {
+                latency: Duration::from_millis(latency.unwrap_or(0)),
+                packet_loss: packet_loss.unwrap_or(0),
+            });
+        }
+    }
+
+    // e.g. `quad --mode versus --bot 1 --bot-lookahead 1`
+    if let Some(players) = arg_value("--bot") {
+        let players = players
+            .split(',')
+            .map(|player| player.parse().expect("Player must be a number"))
+            .collect()
+        ;
+        let weights = match arg_value("--bot-weights") {
+            Some(path) => BotWeights::load(Path::new(&path))
+                .unwrap_or_else(|err| {
+                    panic!("Couldn't load bot weights from {path}: {err}")
+                }),
+            None => BotWeights::default(),
+        };
+        let lookahead = arg_value("--bot-lookahead")
+            .map(|pieces| pieces.parse().expect("Lookahead must be a number"))
+            .unwrap_or(0)
+        ;
+        // only previewed pieces can be thought ahead to
+        let preview = app.world.resource::<Ruleset>().preview;
+        if lookahead > MAX_LOOKAHEAD.min(preview) {
+            panic!(
+                "Lookahead can be at most {} pieces",
+                MAX_LOOKAHEAD.min(preview),
+            );
+        }
+        app.insert_resource(Bots {
+            players,
+            weights,
+            lookahead,
+            playing: HashMap::new(),
+        });
+    }
+
+    // e.g. `quad --finesse-alerts`
+    if arg_flag("--finesse-alerts") {
+        app.insert_resource(FinesseAlerts);
+    }
+
+    // replays and games over the network go straight to playing
+    let networked = app.world.contains_resource::<NetSession>();
+    let initial_state = match app.world.resource::<GameSource>() {
+        GameSource::Live { .. } if !networked => GameState::Menu,
+        _ => GameState::Playing,
+    };
+    app
         .add_plugins(DefaultPlugins)
-        .insert_resource(GravityTimer::new())
-        .insert_resource(MovementXTimer::new())
-        .insert_resource(MovementYTimer::new())
-        .insert_resource(Inputs::new())
-        // make this extensible
-        .insert_resource(GridSize { width: 15, height: 25 })
-        // placeholder value
-        .insert_resource(Origin {
-            pos: GridPos { x: 0, y: 0 },
-            mode: OriginMode::PointCentered,
-        })
-        .add_event::<SpawnEvent>()
+        .add_stage_before(
+            CoreStage::Update,
+            GameTick,
+            SystemStage::parallel().with_run_criteria(run_ticks),
+        )
+        .add_state(initial_state)
+        .init_resource::<GameClock>()
+        .insert_resource(MenuInputs::new())
+        .init_resource::<InputQueue>()
+        .init_resource::<KeyBindings>()
+        .init_resource::<Snapshots>()
+        .insert_resource(HighScores::load())
+        .add_tick_event::<SpawnEvent>()
+        .add_tick_event::<TopOutEvent>()
+        .add_tick_event::<OutOfPiecesEvent>()
+        .add_tick_event::<GoalEvent>()
+        .add_tick_event::<DropEvent>()
+        .add_tick_event::<LockEvent>()
+        .add_tick_event::<LineClearEvent>()
+        .add_tick_event::<PerfectClearEvent>()
+        .add_tick_event::<GarbageEvent>()
+        .add_event::<ScoreEvent>()
+        .add_event::<FinesseFaultEvent>()
+        .add_event::<GameOverEvent>()
+        .add_startup_system_to_stage(StartupStage::PreStartup, load_font)
         .add_startup_system(setup)
-        .add_system(spawn)
-        .add_system(input.after(spawn))
-        .add_system(movement.after(input))
-        .add_system(rotation.after(movement))
-        .add_system(lock.after(rotation))
-        .add_system(update_sprites.after(lock))
+        .add_system_to_stage(CoreStage::PreUpdate, read_keyboard)
+        .add_system_to_stage(CoreStage::PreUpdate, receive_messages)
+        .add_system_to_stage(
+            CoreStage::PreUpdate,
+            roll_back.after(receive_messages),
+        )
+        .add_system_to_stage(GameTick, save_snapshot.before(BeginTick))
+        .add_system_to_stage(GameTick, add_garbage.after(BeginTick))
+        .add_system_to_stage(GameTick, spawn.after(add_garbage))
+        .add_system_to_stage(GameTick, drive_bots.after(spawn))
+        .add_system_to_stage(GameTick, exchange_inputs.after(drive_bots))
+        .add_system_to_stage(GameTick, input.after(exchange_inputs))
+        .add_system_to_stage(GameTick, movement.after(input))
+        .add_system_to_stage(GameTick, rotation.after(movement))
+        .add_system_to_stage(GameTick, lock.after(rotation))
+        .add_system_to_stage(GameTick, clear_lines.after(lock))
+        .add_system_to_stage(GameTick, score.after(clear_lines))
+        .add_system_to_stage(GameTick, level_up.after(score))
+        .add_system_to_stage(GameTick, update_stats.after(score))
+        .add_system_to_stage(GameTick, hold.after(lock))
+        .add_system_to_stage(GameTick, finesse.after(lock))
+        .add_system_to_stage(GameTick, rise_garbage.after(clear_lines))
+        .add_system_to_stage(GameTick, send_garbage.after(score))
+        .add_system_to_stage(GameTick, land_garbage.after(send_garbage))
+        .add_system_to_stage(GameTick, check_goal.after(level_up))
+        .add_system_to_stage(GameTick, end_game.after(check_goal))
+        .add_system_to_stage(GameTick, check_sync.after(end_game))
+        .add_system(watch_session.before(game_over))
+        .add_system(game_over)
+        .add_system(end_session.after(game_over))
+        .add_system(record_high_score.before(finish_replay))
+        .add_system(finish_replay)
+        .add_system(select_mode)
+        .add_system(start_from_menu.after(select_mode))
+        .add_system(pause)
+        .add_system(restart.after(pause))
+        .add_system(back_to_menu.after(restart))
+        .add_system(draw_grid)
+        .add_system(spawn_hud)
+        .add_system(spawn_garbage_meter)
+        .add_system(update_sprites.after(restart))
+        .add_system(update_hud.after(spawn_hud))
+        .add_system(update_garbage_meter.after(spawn_garbage_meter))
+        .add_system_to_stage(CoreStage::PostUpdate, show_overlay)
+        .add_system_to_stage(CoreStage::Last, save_replay)
         .run()
     ;
 }
 
+// value following the given flag on the command line
+fn arg_value(flag: &str) -> Option<String> {
+    env::args().skip_while(|arg| arg != flag).nth(1)
+}
+
+// whether the given flag is on the command line
+fn arg_flag(flag: &str) -> bool {
+    env::args().any(|arg| arg == flag)
+}
+
 fn setup(
     mut commands: Commands,
-    grid_size: Res<GridSize>,
-    mut spawn_notify: EventWriter<SpawnEvent>,
+    source: Res<GameSource>,
+    ruleset: Res<Ruleset>,
+    session: Option<Res<NetSession>>,
+    mut spawn_notify: ResMut<Events<SpawnEvent>>,
 ) {
     commands.spawn(Camera2dBundle::default());
 
-    let heap = vec![
-        HeapEntry::Vacant;
-        (grid_size.width * grid_size.height) as usize
-    ];
-    let heap = Heap {
-        blocks: heap,
-    };
-    commands.insert_resource(heap);
-
-    commands
-        // grid
-        .spawn(SpriteBundle {
-            sprite: Sprite {
-                custom_size: Some(Vec2::new(
-                    grid_size.width as f32 * BLOCK_SIZE,
-                    grid_size.height as f32 * BLOCK_SIZE,
-                )),
-                color: Color::rgb(0.0, 0.0, 0.0),
-                ..Sprite::default()
-            },
-            ..SpriteBundle::default()
-        })
-        // area above the grid where pieces spawn
-        .with_children(|parent| {
+    if matches!(*source, GameSource::Replay(_)) || session.is_some() {
+        start_game(&mut commands, &source, &ruleset, &mut spawn_notify);
+    }
+}
+
+// Lay the grid out behind the blocks of each new board
+fn draw_grid(
+    mut commands: Commands,
+    boards: Query<(Entity, &GridSize), Added<Board>>,
+) {
+    for (board, grid_size) in boards.iter() {
+        commands.entity(board).with_children(|parent| {
             parent
+                // grid
                 .spawn(SpriteBundle {
-                    transform: Transform::from_xyz(0.0, -10.0, 0.0),
                     sprite: Sprite {
                         custom_size: Some(Vec2::new(
                             grid_size.width as f32 * BLOCK_SIZE,
-                            3 as f32 * BLOCK_SIZE,
+                            grid_size.height as f32 * BLOCK_SIZE,
                         )),
-                        color: Color::rgba(1.0, 1.0, 1.0, 1.0),
+                        color: Color::rgb(0.0, 0.0, 0.0),
                         ..Sprite::default()
                     },
                     ..SpriteBundle::default()
                 })
+                // area above the grid where pieces spawn
+                .with_children(|parent| {
+                    parent
+                        .spawn(SpriteBundle {
+                            transform: Transform::from_xyz(
+                                0.0,
+                                (grid_size.height + SPAWN_AREA_HEIGHT) as f32
+                                    * BLOCK_SIZE * 0.5,
+                                0.0,
+                            ),
+                            sprite: Sprite {
+                                custom_size: Some(Vec2::new(
+                                    grid_size.width as f32 * BLOCK_SIZE,
+                                    SPAWN_AREA_HEIGHT as f32 * BLOCK_SIZE,
+                                )),
+                                color: Color::rgba(1.0, 1.0, 1.0, 1.0),
+                                ..Sprite::default()
+                            },
+                            ..SpriteBundle::default()
+                        })
+                    ;
+                })
             ;
-        })
-    ;
-
-    spawn_notify.send(SpawnEvent);
+        });
+    }
 }
 
+// Place each block on its board, which it's positioned relative to
 fn update_sprites(
-    grid_size: Res<GridSize>,
-    mut block: Query<(&GridPos, &mut Transform)>,
+    boards: Query<&GridSize, With<Board>>,
+    mut block: Query<(&GridPos, &Parent, &mut Transform, &mut Visibility)>,
 ) {
-    for (position, mut transform) in block.iter_mut() {
+    for (position, parent, mut transform, mut visibility) in block.iter_mut() {
+        let Ok(grid_size) = boards.get(parent.get()) else { continue };
+
+        // the rest of the hidden rows stay hidden
+        visibility.is_visible =
+            position.y < grid_size.height + SPAWN_AREA_HEIGHT
+        ;
         transform.translation.x = BLOCK_SIZE *
             (position.x as f32 - grid_size.width as f32 * 0.5 + 0.5)
         ;
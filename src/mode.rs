@@ -0,0 +1,234 @@
+use bevy::prelude::*;
+use bevy::utils::Duration;
+use serde::{Deserialize, Serialize};
+use ::core::fmt;
+use ::core::str::FromStr;
+use crate::board::Board;
+use crate::game::{GameSource, GameState};
+use crate::garbage::GarbageRows;
+use crate::heap::{LineClearEvent, PerfectClearEvent};
+use crate::input::{Action, MenuInputs};
+use crate::ruleset::Ruleset;
+use crate::score::Score;
+use crate::tick::GameClock;
+
+
+const MARATHON_LINES: u32 = 150;
+const SPRINT_LINES: u32 = 40;
+const ULTRA_TIME: Duration = Duration::from_secs(120);
+const CHEESE_ROWS: u32 = 10;
+const CHEESE_MESSINESS: u8 = 100;
+// time between the first garbage rows to rise, which shortens by a
+// thirtieth with each row until it's down to the minimum
+pub const SURVIVAL_START_INTERVAL: Duration = Duration::from_secs(4);
+pub const SURVIVAL_MIN_INTERVAL: Duration = Duration::from_millis(500);
+
+// the mode's goal has been reached on a board, which ends the game
+pub struct GoalEvent {
+    pub board: Entity,
+}
+
+// What the player is going for, which decides when a game ends
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum GameMode {
+    // play through the levels, until the given number of lines if any
+    Marathon { lines: Option<u32> },
+    // clear 40 lines as fast as possible
+    Sprint,
+    // score as much as possible in 2 minutes
+    Ultra,
+    // no goal and no topping out; the board clears itself instead
+    Zen,
+    // dig through the given number of garbage rows as fast as possible, the
+    // messiness being how likely (as a percentage) each row's hole is to
+    // move from the one below
+    Cheese { rows: u32, messiness: u8 },
+    // last as long as possible as garbage rises ever faster
+    Survival,
+    // two players side by side, sending each other garbage until one of
+    // them tops out
+    Versus,
+    // a set scenario, loaded from a file into the ruleset
+    Puzzle,
+}
+
+impl GameMode {
+    // in the order they're offered in the menu
+    pub const ALL: [GameMode; 8] = [
+        GameMode::Marathon { lines: Some(MARATHON_LINES) },
+        GameMode::Marathon { lines: None },
+        GameMode::Sprint,
+        GameMode::Ultra,
+        GameMode::Zen,
+        GameMode::Cheese { rows: CHEESE_ROWS, messiness: CHEESE_MESSINESS },
+        GameMode::Survival,
+        GameMode::Versus,
+    ];
+
+    // how many boards are played at once
+    pub fn players(&self) -> usize {
+        match *self {
+            Self::Versus => 2,
+            _ => 1,
+        }
+    }
+
+    // lines that finish the game once cleared
+    pub fn line_goal(&self) -> Option<u32> {
+        match *self {
+            Self::Marathon { lines } => lines,
+            Self::Sprint => Some(SPRINT_LINES),
+            _ => None,
+        }
+    }
+
+    // time after which the game is over
+    pub fn time_limit(&self) -> Option<Duration> {
+        match *self {
+            Self::Ultra => Some(ULTRA_TIME),
+            _ => None,
+        }
+    }
+
+    // whether the level goes up as lines are cleared
+    pub fn levels_up(&self) -> bool {
+        matches!(self, Self::Marathon { .. })
+    }
+
+    pub fn finished(
+        &self,
+        lines: u32,
+        time: Duration,
+        garbage_rows: u32,
+    ) -> bool {
+        self.line_goal().is_some_and(|goal| lines >= goal)
+            || self.time_limit().is_some_and(|limit| time >= limit)
+            || matches!(self, Self::Cheese { .. }) && garbage_rows == 0
+    }
+
+    pub fn description(&self) -> String {
+        match *self {
+            Self::Marathon { lines: Some(lines) } => {
+                format!("Clear {lines} lines")
+            },
+            Self::Marathon { lines: None } => {
+                "Play for as long as you last".to_string()
+            },
+            Self::Sprint => format!("Clear {SPRINT_LINES} lines fast"),
+            Self::Ultra => "Score big in 2 minutes".to_string(),
+            Self::Zen => "No goal, no top-out".to_string(),
+            Self::Cheese { rows, .. } => format!("Dig out {rows} rows"),
+            Self::Survival => "Outlast the garbage".to_string(),
+            Self::Versus => "Outlast each other".to_string(),
+            Self::Puzzle => "Solve the puzzle".to_string(),
+        }
+    }
+}
+
+impl Default for GameMode {
+    fn default() -> Self {
+        Self::ALL[0]
+    }
+}
+
+impl fmt::Display for GameMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Marathon { lines: Some(_) } => write!(f, "Marathon"),
+            Self::Marathon { lines: None } => write!(f, "Endless"),
+            Self::Sprint => write!(f, "Sprint"),
+            Self::Ultra => write!(f, "Ultra"),
+            Self::Zen => write!(f, "Zen"),
+            Self::Cheese { .. } => write!(f, "Cheese"),
+            Self::Survival => write!(f, "Survival"),
+            Self::Versus => write!(f, "Versus"),
+            Self::Puzzle => write!(f, "Puzzle"),
+        }
+    }
+}
+
+impl FromStr for GameMode {
+    type Err = String;
+
+    fn from_str(mode: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|known| known.to_string().to_lowercase() == mode)
+            .ok_or_else(|| format!(
+                "unknown game mode '{mode}' (expected 'marathon', 'endless', \
+                'sprint', 'ultra', 'zen', 'cheese', 'survival' or 'versus')"
+            ))
+    }
+}
+
+
+// See to it that the game ends once the mode's goal is reached
+pub fn check_goal(
+    ruleset: Res<Ruleset>,
+    clock: Res<GameClock>,
+    mut clear_events: EventReader<LineClearEvent>,
+    mut perfect_clear_events: EventReader<PerfectClearEvent>,
+    mut goal_notify: EventWriter<GoalEvent>,
+    boards: Query<(Entity, &Score, &GarbageRows), With<Board>>,
+) {
+    let clears = clear_events.iter().collect::<Vec<_>>();
+    let perfect_clears = perfect_clear_events
+        .iter()
+        .map(|perfect_clear| perfect_clear.board)
+        .collect::<Vec<_>>()
+    ;
+
+    for (board, score, garbage_rows) in boards.iter() {
+        let reached = match &ruleset.puzzle {
+            Some(puzzle) => {
+                let perfect_clear = perfect_clears.contains(&board);
+                clears
+                    .iter()
+                    .filter(|clear| clear.board == board)
+                    .any(|clear| {
+                        puzzle.goal.reached(clear, score.lines, perfect_clear)
+                    })
+            },
+            None => {
+                let time = clock.time();
+                ruleset.mode.finished(score.lines, time, garbage_rows.0)
+            },
+        };
+
+        if reached {
+            goal_notify.send(GoalEvent { board });
+        }
+    }
+}
+
+// Pick the mode of the next game from the menu
+pub fn select_mode(
+    menu_inputs: Res<MenuInputs>,
+    state: Res<State<GameState>>,
+    source: Res<GameSource>,
+    mut ruleset: ResMut<Ruleset>,
+) {
+    // replays keep to the mode they were recorded in, and puzzles stay
+    // puzzles
+    if *state.current() != GameState::Menu
+        || !matches!(*source, GameSource::Live { .. })
+        || ruleset.puzzle.is_some()
+    {
+        return;
+    }
+
+    let step = if menu_inputs.just_pressed(Action::Right) {
+        1
+    } else if menu_inputs.just_pressed(Action::Left) {
+        GameMode::ALL.len() - 1
+    } else {
+        return;
+    };
+    // modes set up from the command line needn't be exactly as listed
+    let current = GameMode::ALL
+        .iter()
+        .position(|mode| mode.to_string() == ruleset.mode.to_string())
+        .unwrap_or(0)
+    ;
+    ruleset.mode = GameMode::ALL[(current + step) % GameMode::ALL.len()];
+}
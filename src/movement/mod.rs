@@ -4,93 +4,177 @@ use bevy::prelude::*;
 use ::core::borrow::Borrow;
 use crate::grid::{GridSize, GridPos};
 use crate::heap::{HeapEntry, Heap};
-use crate::piece::{Block, Origin};
-use crate::input::{Input, Inputs};
+use crate::piece::{ActivePiece, Block, Origin};
+use crate::input::{Action, Inputs};
+use crate::ruleset::Ruleset;
+use crate::tick::TICK;
 pub use self::types::*;
 
 
+// the player moved the piece down themselves, which is worth points
+pub struct DropEvent {
+    pub board: Entity,
+    pub rows: u32,
+    pub hard: bool,
+}
+
+
 pub fn movement(
-    time: Res<Time>,
-    heap: Res<Heap>,
-    grid_size: Res<GridSize>,
-    inputs: Res<Inputs>,
-    mut origin: ResMut<Origin>,
-    mut gravity_timer: ResMut<GravityTimer>,
-    mut move_x_timer: ResMut<MovementXTimer>,
-    mut move_y_timer: ResMut<MovementYTimer>,
-    mut block_pos: Query<&mut GridPos, With<Block>>,
+    ruleset: Res<Ruleset>,
+    mut drop_notify: EventWriter<DropEvent>,
+    mut boards: Query<(
+        Entity,
+        &GridSize,
+        &Heap,
+        &Inputs,
+        &mut Origin,
+        &mut ActivePiece,
+        &mut GravityTimer,
+        &mut MovementXTimer,
+        &mut MovementYTimer,
+    )>,
+    mut blocks: Query<(&Parent, &mut GridPos), With<Block>>,
 ) {
-    // each block of the piece has, appropriately, the `Block` component
-    let mut block_pos = block_pos.iter_mut().collect::<Vec<_>>();
-    let grid_width = grid_size.width;
-
-    // hard drop
-    if inputs.get_action_state(Input::HardDropJustPressed) {
-        while can_move(&block_pos, grid_width, MoveY::Down1, &heap) {
-            block_pos.iter_mut().for_each(|pos| pos.y -= 1);
+    for (
+        board,
+        grid_size,
+        heap,
+        inputs,
+        mut origin,
+        mut piece,
+        mut gravity_timer,
+        mut move_x_timer,
+        mut move_y_timer,
+    ) in boards.iter_mut() {
+        let grid_width = grid_size.width;
+        // each block of the piece has, appropriately, the `Block` component
+        let mut block_pos = blocks
+            .iter_mut()
+            .filter(|(parent, _)| parent.get() == board)
+            .map(|(_, pos)| pos)
+            .collect::<Vec<_>>()
+        ;
+        // a piece spawned this tick only shows up once the tick is over
+        if block_pos.is_empty() {
+            continue;
         }
-        return;
-    }
 
-    // get movement input
-    let (mut move_x, mut move_y) = {
-        use self::Input::*;
+        // hard and sonic drop, the former locking the piece right away and
+        // the latter leaving it to rest on the heap, which counts as a soft
+        // drop
+        if inputs.just_pressed(Action::HardDrop)
+            || inputs.just_pressed(Action::SonicDrop)
+        {
+            let mut rows = 0;
+            while can_move(&block_pos, grid_width, MoveY::Down1, heap) {
+                block_pos.iter_mut().for_each(|pos| pos.y -= 1);
+                origin.pos.y -= 1;
+                rows += 1;
+            }
+            if rows > 0 {
+                piece.last_kick = None;
+                let hard = inputs.just_pressed(Action::HardDrop);
+                drop_notify.send(DropEvent { board, rows, hard });
+            }
+            continue;
+        }
 
+        // get movement input
+        let (mut move_x, mut move_y) = {
+            let move_x = match inputs.resolve_socd(
+                ruleset.socd_mode,
+                Inputs::pressed,
+                Action::Left,
+                Action::Right,
+            ) {
+                Some(Action::Left) => MoveX::Left,
+                Some(_) => MoveX::Right,
+                None => MoveX::Neutral,
+            };
 
-        let left_press = inputs.get_action_state(LeftPressed);
-        let right_press = inputs.get_action_state(RightPressed);
-        let move_x = match (left_press, right_press) {
-            (true, true) | (false, false) => MoveX::Neutral,
-            (true, false) => MoveX::Left,
-            (false, true) => MoveX::Right,
+            if inputs.pressed(Action::SoftDrop) {
+                (move_x, MoveY::Down1)
+            } else {
+                (move_x, MoveY::Neutral)
+            }
         };
 
-        if inputs.get_action_state(SoftDropPressed) {
-            (move_x, MoveY::Down1)
+        // a fresh press moves right away; holding only repeats the movement
+        // once the auto-shift delay has passed, and then only every so often
+        move_x_timer.tick(TICK);
+        if !move_x.is_neutral() {
+            let action = match move_x {
+                MoveX::Left => Action::Left,
+                _ => Action::Right,
+            };
+            let auto_shift = move_x_timer.finished()
+                && inputs.held_duration(action) >= ruleset.auto_shift_delay
+            ;
+            if inputs.just_pressed(action) || auto_shift {
+                move_x_timer.reset();
+            } else {
+                // ignore movement input
+                move_x.set_neutral();
+            }
+        }
+        move_y_timer.tick(TICK);
+        if move_y_timer.just_finished() {
+            move_y_timer.reset();
         } else {
-            (move_x, MoveY::Neutral)
+            move_y.set_neutral();
         }
-    };
-
-    // only allow movement every so often
-    move_x_timer.tick(time.delta());
-    if move_x_timer.just_finished() {
-        move_x_timer.reset();
-    } else {
-        // ignore movement input
-        move_x.set_neutral();
-    }
-    move_y_timer.tick(time.delta());
-    if move_y_timer.just_finished() {
-        move_y_timer.reset();
-    } else {
-        move_y.set_neutral();
-    }
+        let soft_drop = move_y;
 
-    // gravity
-    gravity_timer.tick(time.delta());
-    if gravity_timer.just_finished() {
-        move_y.move_down();
-        gravity_timer.reset();
-    }
+        // gravity
+        gravity_timer.tick(TICK);
+        let gravity_rows = gravity_timer.times_finished_this_tick();
+        if gravity_rows > 0 {
+            move_y.move_down();
+        }
 
-    // check if movement is legal
-    if !can_move(&block_pos, grid_width, move_x, &heap) {
-        move_x.set_neutral();
-    }
-    if !can_move(&block_pos, grid_width, move_y, &heap) {
-        move_y.move_up();
-        if move_y == MoveY::Down1
-            && !can_move(&block_pos, grid_width, MoveY::Down1, &heap)
-        {
-            move_y.set_neutral();
+        // check if movement is legal
+        if !can_move(&block_pos, grid_width, move_x, heap) {
+            move_x.set_neutral();
+        }
+        if !can_move(&block_pos, grid_width, move_y, heap) {
+            move_y.move_up();
+            if move_y == MoveY::Down1
+                && !can_move(&block_pos, grid_width, MoveY::Down1, heap)
+            {
+                move_y.set_neutral();
+            }
         }
-    }
 
-    let offset = (move_x, move_y).to_offset();
-    // apply movement
-    block_pos.iter_mut().for_each(|pos| { **pos += offset; });
-    origin.pos += offset;
+        let offset = (move_x, move_y).to_offset();
+        // gravity gets the blame for whatever the soft drop wouldn't have
+        // moved
+        let soft_rows = -offset.1.max(soft_drop.to_offset().1);
+        if soft_rows > 0 {
+            drop_notify.send(DropEvent {
+                board,
+                rows: soft_rows as u32,
+                hard: false,
+            });
+        }
+        if offset != (0, 0) {
+            piece.last_kick = None;
+        }
+
+        // apply movement
+        block_pos.iter_mut().for_each(|pos| { **pos += offset; });
+        origin.pos += offset;
+
+        // at higher levels, gravity moves the piece by more than a row per
+        // tick
+        for _ in 1..gravity_rows {
+            if !can_move(&block_pos, grid_width, MoveY::Down1, heap) {
+                break;
+            }
+            block_pos.iter_mut().for_each(|pos| pos.y -= 1);
+            origin.pos.y -= 1;
+            piece.last_kick = None;
+        }
+    }
 }
 
 pub fn can_move<Pos, Mov>(
@@ -118,8 +202,9 @@ where
             pos.x >= 0 && pos.x < grid_width && pos.y >= 0
                 && match heap.get((pos.x + pos.y * grid_width) as usize)
             {
-                Some(HeapEntry::Vacant) | None => true,
-                Some(HeapEntry::Occupied) => false,
+                Some(HeapEntry::Vacant) => true,
+                // past the top of the hidden rows
+                Some(HeapEntry::Occupied) | None => false,
             }
         })
 }
@@ -1,24 +1,65 @@
 use bevy::time::{Timer, TimerMode};
-use bevy::prelude::{Deref, DerefMut, Resource};
+use bevy::prelude::{Component, Deref, DerefMut};
+use bevy::utils::Duration;
 
 
-// Newtype wrapper around a `Timer`
+// Newtype wrapper around a `Timer`, kept on each board
 macro_rules! timer {
-    ($ty:ident, $duration:literal) => {
-        #[derive(Deref, DerefMut, Resource)]
+    ($ty:ident) => {
+        timer!($ty, TimerMode::Once);
+    };
+    ($ty:ident, $mode:expr) => {
+        #[derive(Clone, Component, Deref, DerefMut)]
         pub struct $ty(Timer);
 
         impl $ty {
-            pub fn new() -> Self {
-                Self(Timer::from_seconds($duration, TimerMode::Once))
+            pub fn new(duration: Duration) -> Self {
+                Self(Timer::new(duration, $mode))
             }
         }
-    }
+    };
+}
+
+// repeats so that gravity can move the piece by several rows at once
+timer!(GravityTimer, TimerMode::Repeating);
+timer!(MovementXTimer);
+timer!(MovementYTimer);
+// time until the next garbage row rises, in modes where garbage rises
+timer!(GarbageTimer, TimerMode::Repeating);
+
+// How long a piece can rest on the heap before it locks; moving or rotating
+// it starts the timer over, but only so many times unless it makes it lower
+// down than it's been before
+#[derive(Clone, Component)]
+pub struct LockDelay {
+    pub timer: Timer,
+    // times the timer has been started over since the piece was last lower
+    // than ever
+    pub resets: u32,
+    // the lowest row the piece's origin has been on
+    pub lowest: i16,
+    // where the piece was, and how it was turned, the tick before
+    pub last: Option<(i16, i16, u8)>,
 }
 
-timer!(GravityTimer, 0.75);
-timer!(MovementXTimer, 0.08);
-timer!(MovementYTimer, 0.08);
+impl LockDelay {
+    pub fn new(duration: Duration) -> Self {
+        Self {
+            timer: Timer::new(duration, TimerMode::Once),
+            resets: 0,
+            lowest: i16::MAX,
+            last: None,
+        }
+    }
+
+    // start over for the next piece
+    pub fn clear(&mut self) {
+        self.timer.reset();
+        self.resets = 0;
+        self.lowest = i16::MAX;
+        self.last = None;
+    }
+}
 
 pub trait MoveOffset: PartialEq + Sized {
     const NEUTRAL: Self;
@@ -0,0 +1,756 @@
+use bevy::prelude::*;
+use bevy::utils::{Duration, HashMap};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use ::std::collections::VecDeque;
+use ::std::error::Error;
+use ::std::io::{ErrorKind, Read, Write};
+use ::std::mem;
+use ::std::net::{Shutdown, TcpListener, TcpStream};
+use ::std::time::Instant;
+use crate::board::Board;
+use crate::game::{GameOverEvent, GameOverReason, GameState, GameSummary};
+use crate::garbage::PendingGarbage;
+use crate::heap::{Heap, HeapEntry};
+use crate::input::{Action, InputQueue};
+use crate::level::Level;
+use crate::piece::{ActivePiece, Origin};
+use crate::replay::{InputChange, Recording};
+use crate::ruleset::Ruleset;
+use crate::score::Score;
+use crate::stats::Stats;
+use crate::tick::GameClock;
+
+
+// bump whenever the messages change, as both ends have to speak the same
+// version to play
+pub const PROTOCOL_VERSION: u32 = 3;
+
+// ticks between an action being pressed and it taking effect, which gives it
+// a head start on reaching the other end
+pub const INPUT_DELAY: u64 = 2;
+
+// ticks the game may run ahead of the other end's actions, guessing that
+// they haven't changed, and so also how far it may have to roll back
+const MAX_PREDICTION: u64 = 30;
+
+// ticks between comparing boards with the other end
+const HASH_INTERVAL: u64 = 60;
+
+// how long the other end may leave the game waiting before it's given up on
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+// how long a lost packet takes to be sent again
+const RETRANSMIT_TIMEOUT: Duration = Duration::from_millis(200);
+
+// Everything that goes over the wire, one message per line; both ends
+// simulate both boards from the same seed and inputs, so garbage never has
+// to be sent, only checked on by way of board hashes
+#[derive(Serialize, Deserialize)]
+enum Message {
+    // first thing either end says
+    Hello { version: u32 },
+    // from the host, once both ends have said hello
+    Start { seed: u64, ruleset: Box<Ruleset> },
+    // a player's action changes that take effect on the given tick, sent
+    // for every tick whether or not anything changed
+    Inputs { tick: u64, changes: Vec<(Action, bool)> },
+    // what the boards looked like at the end of the given tick
+    Hash { tick: u64, hash: u64 },
+}
+
+// Network conditions to put up with on purpose, to see how the game copes
+// without needing a real network that's that bad
+#[derive(Clone, Copy)]
+pub struct NetConditions {
+    // added to the time every message takes to go out
+    pub latency: Duration,
+    // percentage of packets lost, each of which holds up everything sent
+    // after it until it's sent again, as is the way with TCP
+    pub packet_loss: u8,
+}
+
+// FNV-1a, which unlike the standard library's hasher comes out the same on
+// every platform and build, as both ends' board hashes have to
+struct BoardHasher(u64);
+
+impl BoardHasher {
+    fn new() -> Self {
+        Self(0xcbf2_9ce4_8422_2325)
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 ^= byte as u64;
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+// Hash everything about the boards that both ends have to agree on, in
+// player order, each number at a set size and byte order
+fn hash_boards<'a>(
+    boards: impl IntoIterator<Item = (
+        &'a Board,
+        &'a Heap,
+        &'a Score,
+        &'a ActivePiece,
+        &'a Origin,
+        &'a PendingGarbage,
+    )>,
+) -> u64 {
+    let mut boards = boards.into_iter().collect::<Vec<_>>();
+    boards.sort_by_key(|(board, ..)| board.player);
+    let mut hasher = BoardHasher::new();
+    for (board, heap, score, piece, origin, pending) in boards {
+        hasher.write(&(board.player as u32).to_le_bytes());
+        for entry in &heap.blocks {
+            hasher.write(&[matches!(entry, HeapEntry::Occupied) as u8]);
+        }
+        hasher.write(&score.points.to_le_bytes());
+        hasher.write(&score.lines.to_le_bytes());
+        hasher.write(&[piece.kind as u8, piece.rotation]);
+        hasher.write(&origin.pos.x.to_le_bytes());
+        hasher.write(&origin.pos.y.to_le_bytes());
+        hasher.write(&pending.lines().to_le_bytes());
+    }
+    hasher.finish()
+}
+
+// A stream of messages, which only blocks until the game gets going
+struct Connection {
+    stream: TcpStream,
+    incoming: Vec<u8>,
+    outgoing: Vec<u8>,
+    // messages held back by the simulated conditions, until they're due
+    delayed: VecDeque<(Instant, Vec<u8>)>,
+    conditions: Option<NetConditions>,
+    // the other end hung up, or the connection broke
+    closed: bool,
+}
+
+impl Connection {
+    fn new(stream: TcpStream) -> Self {
+        // inputs go out one tick at a time
+        let _ = stream.set_nodelay(true);
+        Self {
+            stream,
+            incoming: Vec::new(),
+            outgoing: Vec::new(),
+            delayed: VecDeque::new(),
+            conditions: None,
+            closed: false,
+        }
+    }
+
+    fn send(&mut self, message: &Message) {
+        let line = ron::to_string(message).expect("Couldn't encode message");
+        let mut line = line.into_bytes();
+        line.push(b'\n');
+        match self.conditions {
+            Some(conditions) => {
+                let mut due = Instant::now() + conditions.latency;
+                let lost = rand::thread_rng().gen_range(0..100);
+                if lost < conditions.packet_loss {
+                    due += RETRANSMIT_TIMEOUT;
+                }
+                // nothing overtakes what was sent before it
+                if let Some(&(last, _)) = self.delayed.back() {
+                    due = due.max(last);
+                }
+                self.delayed.push_back((due, line));
+            },
+            None => self.outgoing.extend_from_slice(&line),
+        }
+        self.flush();
+    }
+
+    // Write out as much as the stream takes of what's due
+    fn flush(&mut self) {
+        let now = Instant::now();
+        while let Some((_, line)) = self.delayed
+            .front()
+            .filter(|&&(due, _)| due <= now)
+        {
+            self.outgoing.extend_from_slice(line);
+            self.delayed.pop_front();
+        }
+
+        while !self.outgoing.is_empty() && !self.closed {
+            match self.stream.write(&self.outgoing) {
+                Ok(0) => self.closed = true,
+                Ok(written) => {
+                    self.outgoing.drain(..written);
+                },
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(err) if err.kind() == ErrorKind::Interrupted => {},
+                Err(_) => self.closed = true,
+            }
+        }
+    }
+
+    // The next message, if a whole one has come in; anything that can't be
+    // made sense of ends the connection
+    fn receive(&mut self) -> Option<Message> {
+        loop {
+            if let Some(end) = self.incoming.iter().position(|&b| b == b'\n') {
+                let line = self.incoming.drain(..=end).collect::<Vec<_>>();
+                match ron::de::from_bytes(&line[..end]) {
+                    Ok(message) => return Some(message),
+                    Err(err) => {
+                        eprintln!("Couldn't make sense of a message: {err}");
+                        self.closed = true;
+                    },
+                }
+            }
+            if self.closed {
+                return None;
+            }
+
+            let mut buffer = [0; 4096];
+            match self.stream.read(&mut buffer) {
+                Ok(0) => self.closed = true,
+                Ok(read) => self.incoming.extend_from_slice(&buffer[..read]),
+                Err(err) if err.kind() == ErrorKind::WouldBlock => return None,
+                Err(err) if err.kind() == ErrorKind::Interrupted => {},
+                Err(_) => self.closed = true,
+            }
+        }
+    }
+
+    // Say hello and hear it back, in the same version
+    fn greet(&mut self) -> Result<(), Box<dyn Error>> {
+        self.send(&Message::Hello { version: PROTOCOL_VERSION });
+        match self.receive() {
+            Some(Message::Hello { version }) if version == PROTOCOL_VERSION => {
+                Ok(())
+            },
+            Some(Message::Hello { version }) => Err(format!(
+                "the other end speaks protocol version {version}, but only \
+                version {PROTOCOL_VERSION} is supported",
+            ).into()),
+            Some(_) => Err("the other end didn't say hello".into()),
+            None => Err("the other end hung up".into()),
+        }
+    }
+}
+
+// A game of versus against another instance over the network; ticks run on
+// a prediction of the other end's actions (that they stay as they were) up to
+// `MAX_PREDICTION` ticks ahead of what's been heard from it, and the game is
+// rolled back and run again from the first tick the prediction got wrong
+#[derive(Resource)]
+pub struct NetSession {
+    connection: Connection,
+    // the player on this end
+    pub player: usize,
+    // this end's action changes since the last ones were sent off
+    typed: Vec<(Action, bool)>,
+    // each player's action changes by the tick they take effect on, until
+    // they're certain and on record
+    local: VecDeque<(u64, Vec<(Action, bool)>)>,
+    remote: VecDeque<(u64, Vec<(Action, bool)>)>,
+    // the last ticks that each end's actions are known for
+    local_tick: u64,
+    remote_tick: u64,
+    // the first tick that was run on a wrong guess of the other end's
+    // actions, and has to be run again
+    rollback: Option<u64>,
+    // this end's board hashes of ticks that aren't certain yet
+    local_hashes: Vec<(u64, u64)>,
+    // board hashes by tick, from whichever end got to the tick first
+    hashes: HashMap<u64, u64>,
+    desynced: bool,
+    // the end of the game, if it came on a tick that isn't certain yet
+    game_over: Option<GameSummary>,
+    // time since the other end was last heard from
+    silence: Duration,
+}
+
+impl NetSession {
+    fn new(connection: Connection, player: usize) -> Self {
+        Self {
+            connection,
+            player,
+            typed: Vec::new(),
+            local: VecDeque::new(),
+            remote: VecDeque::new(),
+            // no actions are due before the first ones that can be sent
+            local_tick: INPUT_DELAY,
+            remote_tick: INPUT_DELAY,
+            rollback: None,
+            local_hashes: Vec::new(),
+            hashes: HashMap::new(),
+            desynced: false,
+            game_over: None,
+            silence: Duration::ZERO,
+        }
+    }
+
+    // Wait for someone to join on the given port, then settle on the game
+    pub fn host(
+        port: u16,
+        seed: u64,
+        ruleset: &Ruleset,
+    ) -> Result<Self, Box<dyn Error>> {
+        let listener = TcpListener::bind(("0.0.0.0", port))?;
+        println!("Waiting for an opponent on port {port}");
+        let (stream, address) = listener.accept()?;
+        println!("Playing against {address}");
+
+        let mut connection = Connection::new(stream);
+        connection.greet()?;
+        connection.send(&Message::Start {
+            seed,
+            ruleset: Box::new(ruleset.clone()),
+        });
+        connection.stream.set_nonblocking(true)?;
+        Ok(Self::new(connection, 0))
+    }
+
+    // Join the game hosted at the given address, learning its seed and
+    // ruleset
+    pub fn join(
+        address: &str,
+    ) -> Result<(Self, u64, Ruleset), Box<dyn Error>> {
+        let mut connection = Connection::new(TcpStream::connect(address)?);
+        connection.greet()?;
+        let Some(Message::Start { seed, ruleset }) = connection.receive() else {
+            return Err("the host didn't start the game".into());
+        };
+        connection.stream.set_nonblocking(true)?;
+        Ok((Self::new(connection, 1), seed, *ruleset))
+    }
+
+    pub fn simulate(&mut self, conditions: NetConditions) {
+        self.connection.conditions = Some(conditions);
+    }
+
+    // whether the given tick can run, as far as the other end goes
+    pub fn ready(&self, tick: u64) -> bool {
+        self.game_over.is_none() && tick <= self.remote_tick + MAX_PREDICTION
+    }
+
+    // whether both players' actions are known up to the given tick, so that
+    // it won't have to be run again
+    pub fn confirmed(&self, tick: u64) -> bool {
+        tick <= self.remote_tick
+    }
+
+    // whether the given tick has been run before, and so has had the actions
+    // pressed on it sent already
+    pub fn rerun(&self, tick: u64) -> bool {
+        tick + INPUT_DELAY <= self.local_tick
+    }
+
+    pub fn take_rollback(&mut self) -> Option<u64> {
+        self.rollback.take()
+    }
+
+    // Forget whatever came of the ticks from the given one on, which are to
+    // be run again
+    pub fn rewind(&mut self, tick: u64) {
+        self.local_hashes.retain(|&(hash_tick, _)| hash_tick < tick);
+        self.game_over = None;
+    }
+
+    // End the game once its last tick is certain
+    pub fn hold_game_over(&mut self, summary: GameSummary) {
+        self.game_over = Some(summary);
+    }
+
+    pub fn lose_sync(&mut self) {
+        self.desynced = true;
+    }
+
+    // Put actions up to and including the given tick on record, as they're
+    // certain by then
+    fn record(&mut self, mut recording: Option<&mut Recording>, tick: u64) {
+        let player = self.player;
+        for (player, changes) in [
+            (player, &mut self.local),
+            (1 - player, &mut self.remote),
+        ] {
+            while let Some((change_tick, due)) = changes
+                .front()
+                .filter(|(change_tick, _)| *change_tick <= tick)
+            {
+                if let Some(recording) = &mut recording {
+                    for &(action, pressed) in due {
+                        recording.push(InputChange {
+                            tick: *change_tick,
+                            player,
+                            action,
+                            pressed,
+                        });
+                    }
+                }
+                changes.pop_front();
+            }
+        }
+    }
+
+    // Compare with the other end's hash for the same tick, or leave it for
+    // the other end to compare with
+    fn match_hash(&mut self, tick: u64, hash: u64) {
+        match self.hashes.remove(&tick) {
+            Some(other) => self.desynced |= other != hash,
+            None => {
+                self.hashes.insert(tick, hash);
+            },
+        }
+    }
+
+    // Send off what's left to send, then hang up
+    fn close(&mut self) {
+        let _ = self.connection.stream.set_nonblocking(false);
+        self.connection.conditions = None;
+        for (_, line) in mem::take(&mut self.connection.delayed) {
+            self.connection.outgoing.extend(line);
+        }
+        self.connection.flush();
+        let _ = self.connection.stream.shutdown(Shutdown::Both);
+    }
+}
+
+#[cfg(test)]
+impl NetSession {
+    // A session with nobody on the other end, which has a tick to roll back
+    // to
+    pub fn rolling_back(tick: u64) -> Self {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap());
+        Self {
+            rollback: Some(tick),
+            ..Self::new(Connection::new(stream.unwrap()), 0)
+        }
+    }
+}
+
+
+pub fn receive_messages(
+    time: Res<Time>,
+    clock: Res<GameClock>,
+    session: Option<ResMut<NetSession>>,
+) {
+    let Some(mut session) = session else { return };
+
+    session.connection.flush();
+    session.silence += time.delta();
+    while let Some(message) = session.connection.receive() {
+        session.silence = Duration::ZERO;
+        match message {
+            Message::Inputs { tick, changes } => {
+                // the tick was run guessing that nothing changed
+                if tick <= clock.tick && !changes.is_empty() {
+                    let rollback = session.rollback.get_or_insert(tick);
+                    *rollback = tick.min(*rollback);
+                }
+                session.remote_tick = tick;
+                session.remote.push_back((tick, changes));
+            },
+            Message::Hash { tick, hash } => session.match_hash(tick, hash),
+            // the game's already been settled on
+            Message::Hello { .. } | Message::Start { .. } => {},
+        }
+    }
+}
+
+// Send this end's actions off, delayed by a few ticks, and queue up both
+// players' actions that are due this tick, guessing at the other end's if
+// they aren't in yet
+pub fn exchange_inputs(
+    clock: Res<GameClock>,
+    session: Option<ResMut<NetSession>>,
+    recording: Option<ResMut<Recording>>,
+    mut queue: ResMut<InputQueue>,
+) {
+    let Some(mut session) = session else { return };
+    let session = &mut *session;
+
+    let player = session.player;
+    let typed = queue.0
+        .drain(..)
+        .filter(|&(change_player, ..)| change_player == player)
+        .map(|(_, action, pressed)| (action, pressed))
+    ;
+    session.typed.extend(typed);
+
+    if !session.rerun(clock.tick) {
+        let tick = clock.tick + INPUT_DELAY;
+        let changes = mem::take(&mut session.typed);
+        session.connection.send(&Message::Inputs {
+            tick,
+            changes: changes.clone(),
+        });
+        session.local.push_back((tick, changes));
+        session.local_tick = tick;
+    }
+
+    for (player, changes) in [
+        (player, &session.local),
+        (1 - player, &session.remote),
+    ] {
+        let due = changes
+            .iter()
+            .filter(|(change_tick, _)| *change_tick == clock.tick)
+            .flat_map(|(_, due)| due)
+            .map(|&(action, pressed)| (player, action, pressed))
+        ;
+        queue.0.extend(due);
+    }
+
+    let certain = clock.tick.min(session.remote_tick);
+    session.record(recording.map(ResMut::into_inner), certain);
+}
+
+// Every so often, hash both boards and check that the other end's match
+pub fn check_sync(
+    clock: Res<GameClock>,
+    session: Option<ResMut<NetSession>>,
+    boards: Query<(
+        &Board,
+        &Heap,
+        &Score,
+        &ActivePiece,
+        &Origin,
+        &PendingGarbage,
+    )>,
+) {
+    let Some(mut session) = session else { return };
+
+    if clock.tick.is_multiple_of(HASH_INTERVAL) {
+        let hash = hash_boards(boards.iter());
+        session.local_hashes.push((clock.tick, hash));
+    }
+
+    // only the boards of ticks that are certain are worth comparing
+    let (certain, uncertain) = mem::take(&mut session.local_hashes)
+        .into_iter()
+        .partition::<Vec<_>, _>(|&(tick, _)| session.confirmed(tick))
+    ;
+    session.local_hashes = uncertain;
+    for (tick, hash) in certain {
+        session.connection.send(&Message::Hash { tick, hash });
+        session.match_hash(tick, hash);
+    }
+}
+
+// End the game once the end it came to is certain, or when the other end
+// goes quiet, or when the boards have drifted apart
+pub fn watch_session(
+    clock: Res<GameClock>,
+    state: Res<State<GameState>>,
+    session: Option<ResMut<NetSession>>,
+    recording: Option<ResMut<Recording>>,
+    mut game_over_notify: EventWriter<GameOverEvent>,
+    boards: Query<(&Board, &Score, &Level, &Stats)>,
+) {
+    let Some(mut session) = session else { return };
+    if *state.current() != GameState::Playing {
+        return;
+    }
+
+    let gone = session.connection.closed || session.silence > TIMEOUT;
+    let game_over = session.game_over
+        .clone()
+        .filter(|summary| session.confirmed(summary.ticks))
+    ;
+    let summary = match game_over {
+        Some(summary) if !session.desynced => summary,
+        _ => {
+            let reason = if session.desynced {
+                GameOverReason::Desynced
+            } else if gone && !session.ready(clock.tick + 1) {
+                GameOverReason::Disconnected
+            } else {
+                return;
+            };
+            let Some((_, score, level, stats)) = boards
+                .iter()
+                .find(|(board, ..)| board.player == session.player)
+            else {
+                return;
+            };
+            GameSummary {
+                reason,
+                ticks: clock.tick,
+                score: score.points,
+                lines: score.lines,
+                level: level.0,
+                pieces: stats.pieces,
+            }
+        },
+    };
+
+    let certain = clock.tick.min(session.remote_tick);
+    session.record(recording.map(ResMut::into_inner), certain);
+    game_over_notify.send(GameOverEvent { summary });
+}
+
+// Hang up once the game is over, however it ended
+pub fn end_session(
+    mut commands: Commands,
+    game_over_events: EventReader<GameOverEvent>,
+    session: Option<ResMut<NetSession>>,
+) {
+    let Some(mut session) = session else { return };
+    if game_over_events.is_empty() {
+        return;
+    }
+
+    session.close();
+    commands.remove_resource::<NetSession>();
+}
+
+
+#[cfg(test)]
+mod tests {
+    use ::std::thread;
+    use crate::grid::{GridPos, GridSize};
+    use crate::piece::{OriginMode, PieceKind};
+    use super::*;
+
+
+    fn connected() -> (Connection, Connection) {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap());
+        let (server, _) = listener.accept().unwrap();
+        (Connection::new(server), Connection::new(client.unwrap()))
+    }
+
+    // Wait a little for a message that's on its way
+    fn receive(connection: &mut Connection) -> Option<Message> {
+        for _ in 0..100 {
+            if let Some(message) = connection.receive() {
+                return Some(message);
+            }
+            if connection.closed {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        None
+    }
+
+    fn round_trip(message: &Message) -> Message {
+        ron::from_str(&ron::to_string(message).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn messages_come_out_as_they_went_in() {
+        let hello = round_trip(&Message::Hello { version: PROTOCOL_VERSION });
+        assert!(matches!(hello, Message::Hello { version: PROTOCOL_VERSION }));
+
+        let start = round_trip(&Message::Start {
+            seed: u64::MAX,
+            ruleset: Box::new(Ruleset::default()),
+        });
+        let Message::Start { seed, ruleset } = start else { panic!() };
+        assert_eq!(seed, u64::MAX);
+        assert_eq!(ruleset.start_level, Ruleset::default().start_level);
+
+        let inputs = round_trip(&Message::Inputs {
+            tick: 7,
+            changes: vec![(Action::Left, true), (Action::HardDrop, false)],
+        });
+        let Message::Inputs { tick, changes } = inputs else { panic!() };
+        assert_eq!(tick, 7);
+        assert_eq!(changes, [(Action::Left, true), (Action::HardDrop, false)]);
+
+        let hash = round_trip(&Message::Hash { tick: 60, hash: u64::MAX });
+        assert!(matches!(hash, Message::Hash { tick: 60, hash: u64::MAX }));
+    }
+
+    #[test]
+    fn ends_greet_then_start_then_trade_inputs() {
+        let (mut host, mut guest) = connected();
+        let host = thread::spawn(move || {
+            host.greet().unwrap();
+            host.send(&Message::Start {
+                seed: 42,
+                ruleset: Box::new(Ruleset::default()),
+            });
+            host
+        });
+        guest.greet().unwrap();
+        let start = guest.receive();
+        assert!(matches!(start, Some(Message::Start { seed: 42, .. })));
+        let mut host = host.join().unwrap();
+
+        // from here on neither end waits on the other
+        host.stream.set_nonblocking(true).unwrap();
+        guest.stream.set_nonblocking(true).unwrap();
+        assert!(host.receive().is_none());
+        guest.send(&Message::Inputs {
+            tick: INPUT_DELAY,
+            changes: vec![(Action::Right, true)],
+        });
+        guest.send(&Message::Hash { tick: 0, hash: 1 });
+        let Some(Message::Inputs { tick, changes }) = receive(&mut host) else {
+            panic!("the inputs didn't come through");
+        };
+        assert_eq!(tick, INPUT_DELAY);
+        assert_eq!(changes, [(Action::Right, true)]);
+        let hash = receive(&mut host);
+        assert!(matches!(hash, Some(Message::Hash { tick: 0, hash: 1 })));
+
+        drop(guest);
+        assert!(receive(&mut host).is_none());
+        assert!(host.closed);
+    }
+
+    #[test]
+    fn ends_on_other_versions_are_turned_away() {
+        let (mut host, mut guest) = connected();
+        guest.send(&Message::Hello { version: PROTOCOL_VERSION + 1 });
+        assert!(host.greet().is_err());
+    }
+
+    #[test]
+    fn hashes_are_fnv_1a() {
+        let hash = |bytes: &[u8]| {
+            let mut hasher = BoardHasher::new();
+            hasher.write(bytes);
+            hasher.finish()
+        };
+        assert_eq!(hash(b""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(hash(b"a"), 0xaf63_dc4c_8601_ec8c);
+        assert_eq!(hash(b"foobar"), 0x8594_4171_f739_67e8);
+    }
+
+    #[test]
+    fn ends_with_the_same_boards_agree_on_their_hash() {
+        let size = GridSize { width: 4, height: 3, hidden_height: 1 };
+        let board = |player| (
+            Board { player },
+            Heap::new(size),
+            Score::default(),
+            ActivePiece { kind: PieceKind::T, rotation: 0, last_kick: None },
+            Origin {
+                pos: GridPos { x: 1, y: 3 },
+                mode: OriginMode::BlockCentered,
+            },
+            PendingGarbage::default(),
+        );
+        let hash = |boards: &[_]| hash_boards(boards.iter().map(
+            |(board, heap, score, piece, origin, pending)| {
+                (board, heap, score, piece, origin, pending)
+            },
+        ));
+        let boards = [board(0), board(1)];
+        let swapped = [board(1), board(0)];
+        assert_eq!(hash(&boards), hash(&swapped));
+
+        let mut moved = [board(0), board(1)];
+        moved[1].4.pos.x += 1;
+        assert_ne!(hash(&boards), hash(&moved));
+        let mut scored = [board(0), board(1)];
+        scored[0].2.points += 100;
+        assert_ne!(hash(&boards), hash(&scored));
+    }
+}
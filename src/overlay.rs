@@ -0,0 +1,216 @@
+use bevy::prelude::*;
+use bevy::utils::Duration;
+use crate::board::Board;
+use crate::game::{GameOverReason, GameSource, GameState, GameSummary};
+use crate::high_scores::{HighScore, HighScores, Ranking, format_date};
+use crate::mode::GameMode;
+use crate::ruleset::Ruleset;
+use crate::stats::Stats;
+use crate::tick::GameClock;
+use crate::BLOCK_SIZE;
+
+
+// font for everything written on screen
+#[derive(Resource)]
+pub struct UiFont(pub Handle<Font>);
+
+// text, and the backdrop behind it, laid over the grid outside of gameplay
+#[derive(Component)]
+pub struct Overlay;
+
+
+pub fn load_font(mut commands: Commands, asset_server: Res<AssetServer>) {
+    let font = asset_server.load("fonts/DejaVuSansMono.ttf");
+    commands.insert_resource(UiFont(font));
+}
+
+pub fn show_overlay(
+    mut commands: Commands,
+    state: Res<State<GameState>>,
+    font: Res<UiFont>,
+    source: Res<GameSource>,
+    ruleset: Res<Ruleset>,
+    summary: Option<Res<GameSummary>>,
+    clock: Res<GameClock>,
+    high_scores: Res<HighScores>,
+    ranking: Option<Res<Ranking>>,
+    boards: Query<(&Board, &Stats)>,
+    overlay: Query<Entity, With<Overlay>>,
+) {
+    // the mode can be changed from the menu
+    if !state.is_changed() && !ruleset.is_changed() {
+        return;
+    }
+
+    overlay
+        .iter()
+        .for_each(|entity| commands.entity(entity).despawn_recursive())
+    ;
+
+    let text = match state.current() {
+        GameState::Playing => return,
+        GameState::Menu => {
+            let mode = match *source {
+                GameSource::Live { .. } => format!("< {} >", ruleset.mode),
+                GameSource::Replay(_) => format!("Replay: {}", ruleset.mode),
+            };
+            let best = high_scores
+                .table(&ruleset)
+                .first()
+                .map(|best| format!(
+                    "\n\nBest {}\non {}",
+                    best_result(ruleset.mode, best),
+                    format_date(best.date),
+                ))
+                .unwrap_or_default()
+            ;
+            let description = match &ruleset.puzzle {
+                Some(puzzle) => puzzle.goal.to_string(),
+                None => ruleset.mode.description(),
+            };
+            format!("quad\n\n{mode}\n{description}{best}\n\nEnter: play")
+        },
+        GameState::Paused => {
+            "Paused\n\nP: resume\nR: restart\nM: menu".to_string()
+        },
+        GameState::GameOver => {
+            let results = summary
+                .map(|summary| {
+                    let heading = match summary.reason {
+                        GameOverReason::Finished
+                            if ruleset.mode == GameMode::Puzzle =>
+                        {
+                            "Solved!".to_string()
+                        },
+                        GameOverReason::Finished => "Finished".to_string(),
+                        reason @ (GameOverReason::Won(_)
+                            | GameOverReason::Draw) => reason.to_string(),
+                        reason => format!("Game over\n\n{reason}"),
+                    };
+                    let mut boards = boards.iter().collect::<Vec<_>>();
+                    boards.sort_by_key(|(board, _)| board.player);
+                    let stats = match boards[..] {
+                        [(_, stats)] => format!(
+                            "\n{} pieces, {:.2} PPS\n{:.2} KPP, {:.1} APM\n\
+                            {} finesse faults",
+                            stats.pieces,
+                            stats.pieces_per_second(&clock),
+                            stats.keys_per_piece(),
+                            stats.attack_per_minute(&clock),
+                            stats.finesse_faults,
+                        ),
+                        // only the gist for each of several players
+                        _ => boards
+                            .iter()
+                            .map(|(board, stats)| format!(
+                                "\nPlayer {}: {:.2} PPS, {:.1} APM",
+                                board.player + 1,
+                                stats.pieces_per_second(&clock),
+                                stats.attack_per_minute(&clock),
+                            ))
+                            .collect(),
+                    };
+                    let ranking = match ranking.and_then(|ranking| ranking.0) {
+                        Some(0) => "\n\nNew personal best!".to_string(),
+                        Some(rank) => {
+                            format!("\n\nNo. {} of the best", rank + 1)
+                        },
+                        None => String::new(),
+                    };
+                    format!(
+                        "{heading}\n{}\n\n{}{stats}{ranking}",
+                        ruleset.mode,
+                        results(ruleset.mode, &summary),
+                    )
+                })
+                .unwrap_or_default()
+            ;
+            format!("{results}\n\nR: retry\nM: menu")
+        },
+    };
+
+    commands
+        .spawn((
+            SpriteBundle {
+                sprite: Sprite {
+                    custom_size: Some(Vec2::new(
+                        12.0 * BLOCK_SIZE,
+                        20.0 * BLOCK_SIZE,
+                    )),
+                    color: Color::rgba(0.0, 0.0, 0.0, 0.8),
+                    ..Sprite::default()
+                },
+                // above the blocks
+                transform: Transform::from_xyz(0.0, 0.0, 5.0),
+                ..SpriteBundle::default()
+            },
+            Overlay,
+        ))
+        .with_children(|parent| {
+            parent.spawn(Text2dBundle {
+                text: Text::from_section(text, TextStyle {
+                    font: font.0.clone(),
+                    font_size: 24.0,
+                    color: Color::WHITE,
+                })
+                .with_alignment(TextAlignment::CENTER),
+                transform: Transform::from_xyz(0.0, 0.0, 1.0),
+                ..Text2dBundle::default()
+            });
+        })
+    ;
+}
+
+// What's worth knowing about a finished game depends on its mode
+fn results(mode: GameMode, summary: &GameSummary) -> String {
+    let time = format_time(summary.time());
+    match mode {
+        GameMode::Marathon { .. } => format!(
+            "Score {}\nLines {}\nLevel {}\nTime {time}",
+            summary.score,
+            summary.lines,
+            summary.level,
+        ),
+        GameMode::Sprint => format!(
+            "Time {time}\nLines {}/{}",
+            summary.lines,
+            mode.line_goal().unwrap_or_default(),
+        ),
+        GameMode::Ultra => {
+            format!("Score {}\nLines {}", summary.score, summary.lines)
+        },
+        GameMode::Cheese { .. } => {
+            format!("Time {time}\nPieces {}", summary.pieces)
+        },
+        GameMode::Survival => {
+            format!("Time {time}\nLines {}", summary.lines)
+        },
+        GameMode::Puzzle | GameMode::Versus => format!("Time {time}"),
+        GameMode::Zen => format!(
+            "Score {}\nLines {}\nTime {time}",
+            summary.score,
+            summary.lines,
+        ),
+    }
+}
+
+// what a mode's games are ranked by
+fn best_result(mode: GameMode, best: &HighScore) -> String {
+    match mode {
+        GameMode::Sprint | GameMode::Cheese { .. } | GameMode::Survival => {
+            format_time(best.time)
+        },
+        _ => best.score.to_string(),
+    }
+}
+
+// e.g. "1:23.45"
+pub fn format_time(time: Duration) -> String {
+    let centis = time.as_millis() / 10;
+    format!(
+        "{}:{:02}.{:02}",
+        centis / 6000,
+        centis / 100 % 60,
+        centis % 100,
+    )
+}
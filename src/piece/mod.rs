@@ -1,8 +1,18 @@
 mod defaults;
 
 use bevy::prelude::*;
-use rand::Rng;
+use bevy::ecs::system::EntityCommands;
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+use serde::{Deserialize, Serialize};
+use ::std::collections::VecDeque;
 use crate::grid::{GridSize, GridPos};
+use crate::finesse::PieceInputs;
+use crate::heap::{Heap, LockEvent};
+use crate::input::{Action, Inputs};
+use crate::movement::{LockDelay, MoveNeutral, can_move};
+use crate::game::{TopOut, TopOutEvent};
+use crate::ruleset::Ruleset;
 use crate::BLOCK_SIZE;
 use self::defaults::*;
 
@@ -11,10 +21,109 @@ use self::defaults::*;
 #[derive(Debug, Component)]
 pub struct Block;
 
-// the current piece has been locked, and a new piece will be spawned
-pub struct SpawnEvent;
+// the current piece has been locked, and a new piece will be spawned on the
+// board
+#[derive(Clone)]
+pub struct SpawnEvent {
+    pub board: Entity,
+    // the current piece was put on hold, rather than locked
+    pub held: bool,
+}
+
+// a new piece was due on the board, but the queue had run dry
+pub struct OutOfPiecesEvent {
+    pub board: Entity,
+}
+
+// pieces to be played in the given order instead of at random
+#[derive(Clone, Component)]
+pub struct PieceQueue(pub VecDeque<PieceKind>);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[derive(Serialize, Deserialize)]
+pub enum PieceKind {
+    I,
+    O,
+    T,
+    S,
+    Z,
+    L,
+    J,
+}
+
+impl PieceKind {
+    pub const ALL: [PieceKind; 7] = [
+        PieceKind::I,
+        PieceKind::O,
+        PieceKind::T,
+        PieceKind::S,
+        PieceKind::Z,
+        PieceKind::L,
+        PieceKind::J,
+    ];
+
+    pub fn color(self) -> Color {
+        match self {
+            PieceKind::I => I_COLOR,
+            PieceKind::O => O_COLOR,
+            PieceKind::T => T_COLOR,
+            PieceKind::S => S_COLOR,
+            PieceKind::Z => Z_COLOR,
+            PieceKind::L => L_COLOR,
+            PieceKind::J => J_COLOR,
+        }
+    }
+}
+
+// what there is to know about the current piece besides its blocks
+#[derive(Clone, Copy, Component)]
+pub struct ActivePiece {
+    pub kind: PieceKind,
+    // quarter turns clockwise from the orientation the piece spawned in
+    pub rotation: u8,
+    // the kick that the last rotation took (0 if none was needed), unless
+    // the piece has moved since
+    pub last_kick: Option<usize>,
+}
+
+// source of the piece sequence; seeded so that games can be replayed
+#[derive(Clone, Component)]
+pub struct Randomizer {
+    pub rng: StdRng,
+    // pieces drawn ahead of time, to be shown coming up
+    preview: VecDeque<PieceKind>,
+}
 
-#[derive(Clone, Copy, Resource)]
+impl Randomizer {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            rng: StdRng::seed_from_u64(seed),
+            preview: VecDeque::new(),
+        }
+    }
+
+    // Draw the next piece, keeping the given number of pieces after it
+    // drawn already
+    pub fn next_piece(&mut self, preview: usize) -> PieceKind {
+        while self.preview.len() <= preview {
+            let kind = PieceKind::ALL[self.rng.gen_range(0..7)];
+            self.preview.push_back(kind);
+        }
+        self.preview.pop_front().unwrap()
+    }
+}
+
+// the piece set aside for later, which can be swapped with the current piece
+// once per piece
+#[derive(Clone, Copy, Component, Default)]
+pub struct Hold {
+    pub piece: Option<PieceKind>,
+    // the current piece came out of hold, and has to lock before holding
+    // again
+    pub used: bool,
+}
+
+#[derive(Clone, Copy, Component)]
 pub struct Origin {
     pub pos: GridPos,
     pub mode: OriginMode,
@@ -31,28 +140,33 @@ pub enum OriginMode {
 }
 
 
-pub fn spawn(
-    mut commands: Commands,
-    grid_size: Res<GridSize>,
-    mut origin: ResMut<Origin>,
-    spawn_update: EventReader<SpawnEvent>,
-) {
-    if spawn_update.is_empty() {
-        return;
+// The pieces coming after the current one, as far as the player gets to see
+// them
+pub fn coming(
+    randomizer: &Randomizer,
+    queue: Option<&PieceQueue>,
+    preview: usize,
+) -> Vec<PieceKind> {
+    match queue {
+        Some(queue) => queue.0.iter().take(preview).copied().collect(),
+        None => randomizer.preview.iter().take(preview).copied().collect(),
     }
-    spawn_update.clear();
-
-    let piece_variant_idx: u16 = rand::thread_rng().gen_range(0..7);
-    let (positions, origin_mode, color) = match piece_variant_idx {
-        0 => (I_POS, I_ORIGIN_MODE, I_COLOR),
-        1 => (O_POS, O_ORIGIN_MODE, O_COLOR),
-        2 => (T_POS, T_ORIGIN_MODE, T_COLOR),
-        3 => (S_POS, S_ORIGIN_MODE, S_COLOR),
-        4 => (Z_POS, Z_ORIGIN_MODE, Z_COLOR),
-        5 => (L_POS, L_ORIGIN_MODE, L_COLOR),
-        6 => (J_POS, J_ORIGIN_MODE, J_COLOR),
-        _ => unreachable!(),
-//         unimplemented: other variants
+}
+
+// Where a piece spawns, centered right above the visible field, along with
+// its origin there
+pub fn spawn_position(
+    kind: PieceKind,
+    grid_size: GridSize,
+) -> ([GridPos; 4], Origin) {
+    let (positions, origin_mode) = match kind {
+        PieceKind::I => (I_POS, I_ORIGIN_MODE),
+        PieceKind::O => (O_POS, O_ORIGIN_MODE),
+        PieceKind::T => (T_POS, T_ORIGIN_MODE),
+        PieceKind::S => (S_POS, S_ORIGIN_MODE),
+        PieceKind::Z => (Z_POS, Z_ORIGIN_MODE),
+        PieceKind::L => (L_POS, L_ORIGIN_MODE),
+        PieceKind::J => (J_POS, J_ORIGIN_MODE),
     };
 
     let min_x = positions.iter().map(|pos| pos.0).min().unwrap();
@@ -64,35 +178,217 @@ pub fn spawn(
     let shift_x = ((grid_size.width - extent_x) / 2) - min_x;
     let shift_y = grid_size.height - min_y;
 
-    origin.pos = GridPos { x: shift_x, y: shift_y };
-    origin.mode = origin_mode;
+    let positions = positions.map(|(x, y)| GridPos {
+        x: x + shift_x,
+        y: y + shift_y,
+    });
+    let origin = Origin {
+        pos: GridPos { x: shift_x, y: shift_y },
+        mode: origin_mode,
+    };
+    (positions, origin)
+}
+
+pub fn spawn(
+    mut commands: Commands,
+    ruleset: Res<Ruleset>,
+    mut spawn_events: EventReader<SpawnEvent>,
+    mut top_out_notify: EventWriter<TopOutEvent>,
+    mut out_of_pieces_notify: EventWriter<OutOfPiecesEvent>,
+    mut boards: Query<(
+        &GridSize,
+        &Heap,
+        &mut Origin,
+        &mut ActivePiece,
+        &mut Randomizer,
+        &mut Hold,
+        Option<&mut PieceQueue>,
+    )>,
+) {
+    for &SpawnEvent { board, held } in spawn_events.iter() {
+        let Ok((
+            &grid_size,
+            heap,
+            mut origin,
+            mut piece,
+            mut randomizer,
+            mut hold,
+            queue,
+        )) = boards.get_mut(board) else {
+            continue;
+        };
 
-    for (x, y) in positions {
-        let pos = GridPos {
-            x: x + shift_x,
-            y: y + shift_y,
+        // the piece that was on hold comes back in place of the next one
+        let unheld = if held { hold.piece.replace(piece.kind) } else { None };
+        hold.used = held;
+        let kind = match (unheld, queue) {
+            (Some(kind), _) => kind,
+            (None, Some(mut queue)) => {
+                let Some(kind) = queue.0.pop_front() else {
+                    out_of_pieces_notify.send(OutOfPiecesEvent { board });
+                    continue;
+                };
+                kind
+            },
+            (None, None) => randomizer.next_piece(ruleset.preview),
         };
+        let (positions, spawn_origin) = spawn_position(kind, grid_size);
+
+        *origin = spawn_origin;
+        *piece = ActivePiece { kind, rotation: 0, last_kick: None };
+        // a piece spawned into the heap is still shown, but never played
+        let block_out = ruleset.top_out.block_out
+            && !can_move(positions, grid_size.width, MoveNeutral, heap)
+        ;
+        if block_out {
+            top_out_notify.send(TopOutEvent {
+                board,
+                top_out: TopOut::BlockOut,
+            });
+        }
+
+        for pos in positions {
+            let color = kind.color();
+            let mut block = spawn_block(&mut commands, board, pos, color);
+            if !block_out {
+                block.insert(Block);
+            }
+        }
+    }
+}
+
+// Put the current piece on hold once asked to, unless it's locked already or
+// came out of hold itself
+pub fn hold(
+    mut commands: Commands,
+    mut lock_events: EventReader<LockEvent>,
+    mut spawn_notify: EventWriter<SpawnEvent>,
+    mut boards: Query<(
+        Entity,
+        &Inputs,
+        &Hold,
+        &mut LockDelay,
+        Option<&PieceQueue>,
+    )>,
+    blocks: Query<(Entity, &Parent), With<Block>>,
+) {
+    let locked = lock_events
+        .iter()
+        .map(|lock| lock.board)
+        .collect::<Vec<_>>()
+    ;
+    for (board, inputs, hold, mut lock_delay, queue) in boards.iter_mut() {
+        // the piece's blocks are part of the heap by now
+        if locked.contains(&board) {
+            continue;
+        }
+        // a fixed queue may have nothing left to take the piece's place
+        let replaced = hold.piece.is_some()
+            || queue.is_none_or(|queue| !queue.0.is_empty())
+        ;
+        if !inputs.just_pressed(Action::Hold) || hold.used || !replaced {
+            continue;
+        }
 
-        commands
-            .spawn((
-                SpriteBundle {
-                    sprite: Sprite {
-                        custom_size: Some(Vec2::splat(BLOCK_SIZE)),
-                        color,
-                        ..Sprite::default()
-                    },
-                    transform: Transform::from_translation(
-                        Vec3::new(
-                            x as f32 * BLOCK_SIZE,
-                            y as f32 * BLOCK_SIZE,
-                            1.0,
-                        ),
-                    ),
-                    ..SpriteBundle::default()
-                },
-                Block,
-                pos,
-            ))
+        let mut piece_blocks = blocks
+            .iter()
+            .filter(|(_, parent)| parent.get() == board)
+            .peekable()
         ;
+        // between pieces
+        if piece_blocks.peek().is_none() {
+            continue;
+        }
+        for (block, _) in piece_blocks {
+            commands.entity(block).despawn();
+        }
+        // the piece coming out of hold is handled afresh
+        lock_delay.clear();
+        commands.entity(board).insert(PieceInputs::default());
+        spawn_notify.send(SpawnEvent { board, held: true });
+    }
+}
+
+// Put a block on a board's grid, whether as part of a piece or of the heap;
+// its position on screen is relative to the board it's a child of
+pub fn spawn_block<'w, 's, 'a>(
+    commands: &'a mut Commands<'w, 's>,
+    board: Entity,
+    pos: GridPos,
+    color: Color,
+) -> EntityCommands<'w, 's, 'a> {
+    let mut block = commands.spawn((
+        SpriteBundle {
+            sprite: Sprite {
+                custom_size: Some(Vec2::splat(BLOCK_SIZE)),
+                color,
+                ..Sprite::default()
+            },
+            transform: Transform::from_translation(
+                Vec3::new(
+                    pos.x as f32 * BLOCK_SIZE,
+                    pos.y as f32 * BLOCK_SIZE,
+                    1.0,
+                ),
+            ),
+            ..SpriteBundle::default()
+        },
+        pos,
+    ));
+    block.set_parent(board);
+    block
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+
+    const SIZE: GridSize = GridSize { width: 10, height: 4, hidden_height: 4 };
+
+    #[test]
+    fn the_preview_shows_the_pieces_to_come() {
+        let mut randomizer = Randomizer::new(7);
+        randomizer.next_piece(3);
+        let preview = coming(&randomizer, None, 3);
+        assert_eq!(preview.len(), 3);
+
+        // a longer preview only draws further ahead
+        assert!((0..3).map(|_| randomizer.next_piece(5)).eq(preview));
+        assert_eq!(coming(&randomizer, None, 5).len(), 5);
+    }
+
+    #[test]
+    fn held_pieces_are_swapped_once_per_piece() {
+        let mut world = World::new();
+        world.insert_resource(Ruleset::default());
+        world.init_resource::<Events<SpawnEvent>>();
+        world.init_resource::<Events<TopOutEvent>>();
+        world.init_resource::<Events<OutOfPiecesEvent>>();
+        let board = world.spawn((
+            SIZE,
+            Heap::new(SIZE),
+            spawn_position(PieceKind::T, SIZE).1,
+            ActivePiece { kind: PieceKind::T, rotation: 0, last_kick: None },
+            Randomizer::new(0),
+            Hold::default(),
+            PieceQueue([PieceKind::O, PieceKind::S].into()),
+        )).id();
+        // the same system all along, so that events are only read once
+        let mut stage = SystemStage::single(spawn);
+        let mut spawn = |held| {
+            world.send_event(SpawnEvent { board, held });
+            stage.run(&mut world);
+            let kind = world.get::<ActivePiece>(board).unwrap().kind;
+            let hold = world.get::<Hold>(board).unwrap();
+            (kind, hold.piece, hold.used)
+        };
+
+        // with nothing on hold, the next piece comes instead
+        assert_eq!(spawn(true), (PieceKind::O, Some(PieceKind::T), true));
+        assert_eq!(spawn(true), (PieceKind::T, Some(PieceKind::O), true));
+        // locking a piece lets the next one be held
+        assert_eq!(spawn(false), (PieceKind::S, Some(PieceKind::O), false));
     }
 }
@@ -0,0 +1,357 @@
+use ::std::cmp::Reverse;
+use ::std::collections::{BTreeMap, BinaryHeap, HashMap};
+use crate::grid::{GridPos, GridSize};
+use crate::heap::{Heap, HeapEntry};
+use crate::movement::{MoveOffset, MoveX, MoveY, can_move};
+use crate::piece::{ActivePiece, Origin, PieceKind, spawn_position};
+use crate::rotation::{Rotate, TSpin, rotate_piece, t_spin};
+
+
+// rows between a piece and the heap below which rotations and kicks can't
+// reach the heap
+const CLEARANCE: i16 = 4;
+
+// One input's worth of handling a piece
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Step {
+    Move(Motion),
+    Rotate(Rotate),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Motion {
+    // by one column
+    Left,
+    Right,
+    // held until the piece hits something
+    DasLeft,
+    DasRight,
+    // held for the given number of rows, which may take it all the way down
+    SoftDrop(i16),
+    HardDrop,
+}
+
+// Where a piece can end up locking, and a way of getting it there
+#[derive(Clone)]
+pub struct Placement {
+    pub blocks: Vec<GridPos>,
+    // the piece was rotated into place (kicked or not), with nothing moving
+    // it after, whatever kind of piece it is
+    pub spin: bool,
+    // a spin that the T piece scores for
+    pub t_spin: Option<TSpin>,
+    // the last step is the hard drop that locks the piece
+    pub path: Vec<Step>,
+    // presses it takes, the hard drop counting as none
+    pub inputs: u32,
+}
+
+// a piece on its way to a placement, and the step that took it there from
+// the one it came from
+#[derive(Clone)]
+struct Falling {
+    blocks: Vec<GridPos>,
+    origin: Origin,
+    piece: ActivePiece,
+    from: Option<(usize, Step)>,
+    inputs: u32,
+}
+
+impl Falling {
+    // all that matters to what comes next, the blocks following from the
+    // piece's origin and orientation, and a spin from the last kick
+    fn key(&self) -> (i16, i16, u8, Option<usize>) {
+        let pos = self.origin.pos;
+        (pos.x, pos.y, self.piece.rotation, self.piece.last_kick)
+    }
+
+    // Moved for as long as the motion goes on, along with how far it went
+    // down; none if it can't move at all (except for a hard drop, which locks
+    // the piece where it is)
+    fn moved(
+        &self,
+        motion: Motion,
+        heap: &Heap,
+        grid_width: i16,
+    ) -> Option<(Self, i16)> {
+        use self::Motion::*;
+
+
+        // how far the piece goes, if it isn't stopped first
+        let (movement, limit) = match motion {
+            Left => ((MoveX::Left, MoveY::Neutral), Some(1)),
+            Right => ((MoveX::Right, MoveY::Neutral), Some(1)),
+            DasLeft => ((MoveX::Left, MoveY::Neutral), None),
+            DasRight => ((MoveX::Right, MoveY::Neutral), None),
+            SoftDrop(rows) => ((MoveX::Neutral, MoveY::Down1), Some(rows)),
+            HardDrop => ((MoveX::Neutral, MoveY::Down1), None),
+        };
+        let offset = movement.to_offset();
+
+        let mut moved = self.clone();
+        let mut steps = 0;
+        while limit.is_none_or(|limit| steps < limit)
+            && can_move(&moved.blocks, grid_width, movement, heap)
+        {
+            moved.blocks.iter_mut().for_each(|pos| *pos += offset);
+            moved.origin.pos += offset;
+            steps += 1;
+        }
+        if steps == 0 {
+            return (motion == HardDrop).then_some((moved, 0));
+        }
+        moved.piece.last_kick = None;
+        Some((moved, -offset.1 * steps))
+    }
+
+    fn rotated(
+        &self,
+        rotate: Rotate,
+        heap: &Heap,
+        grid_width: i16,
+    ) -> Option<Self> {
+        let (blocks, origin, kick) =
+            rotate_piece(&self.blocks, self.origin, rotate, heap, grid_width)?;
+        Some(Self {
+            blocks,
+            origin,
+            piece: ActivePiece {
+                rotation: (self.piece.rotation + rotate.turns()) % 4,
+                last_kick: Some(kick),
+                ..self.piece
+            },
+            ..self.clone()
+        })
+    }
+}
+
+
+// Every placement a piece of the given kind can reach from where it spawns
+pub fn placements(
+    heap: &Heap,
+    kind: PieceKind,
+    grid_size: GridSize,
+) -> Vec<Placement> {
+    let (blocks, origin) = spawn_position(kind, grid_size);
+    let piece = ActivePiece { kind, rotation: 0, last_kick: None };
+    placements_from(heap, grid_size.width, &blocks, origin, piece)
+}
+
+// Every placement the piece can reach from where it is, each by the fewest
+// inputs there are to it, and told apart by its blocks and whether it's a
+// spin (and a T-spin); the piece is moved the way the game moves it, gravity
+// and the lock delay being assumed to leave enough time for the inputs
+pub fn placements_from(
+    heap: &Heap,
+    grid_width: i16,
+    blocks: &[GridPos],
+    origin: Origin,
+    piece: ActivePiece,
+) -> Vec<Placement> {
+    // above the heap (with room for rotating and kicking), the piece moves
+    // the same at any height, so a soft drop only stops on each row once the
+    // piece is close to it
+    let surface = heap.blocks
+        .iter()
+        .rposition(|entry| matches!(entry, HeapEntry::Occupied))
+        .map_or(0, |idx| idx as i16 / grid_width + 1)
+    ;
+    let start = Falling {
+        blocks: blocks.to_vec(),
+        origin,
+        piece,
+        from: None,
+        inputs: 0,
+    };
+
+    // hard dropped pieces, by their blocks and whether they spun
+    let mut locked = BTreeMap::new();
+    let mut lock = |falling: &Falling, idx: usize| {
+        let mut blocks = falling.blocks
+            .iter()
+            .map(|pos| (pos.x, pos.y))
+            .collect::<Vec<_>>()
+        ;
+        blocks.sort_unstable();
+        let spin = falling.piece.last_kick.is_some();
+        let t_spin = t_spin(&falling.piece, &falling.origin, heap, grid_width);
+        let key = (blocks, spin, t_spin);
+        let known = locked.get(&key);
+        if known.is_none_or(|&(inputs, _)| inputs > falling.inputs) {
+            locked.insert(key, (falling.inputs, idx));
+        }
+    };
+
+    // cheapest first, ties going to whichever was found first
+    let mut best = HashMap::from([(start.key(), 0)]);
+    let mut found = vec![start];
+    let mut queue = BinaryHeap::from([Reverse((0, 0))]);
+    while let Some(Reverse((inputs, idx))) = queue.pop() {
+        let falling = found[idx].clone();
+        if best.get(&falling.key()).is_some_and(|&best| best < inputs) {
+            continue;
+        }
+
+        let bottom = falling.blocks.iter().map(|pos| pos.y).min().unwrap();
+        let soft_rows = (bottom - surface - CLEARANCE).max(1);
+        let motions = [
+            Motion::Left,
+            Motion::Right,
+            Motion::DasLeft,
+            Motion::DasRight,
+            Motion::SoftDrop(soft_rows),
+            Motion::SoftDrop(i16::MAX),
+            Motion::HardDrop,
+        ];
+        let moves = motions.into_iter().filter_map(|motion| {
+            let (moved, rows) = falling.moved(motion, heap, grid_width)?;
+            // soft dropping all the way down goes only as far as there is
+            let motion = match motion {
+                Motion::SoftDrop(_) => Motion::SoftDrop(rows),
+                motion => motion,
+            };
+            Some((Step::Move(motion), moved))
+        });
+        let rotations = [
+            Rotate::Clockwise,
+            Rotate::Counterclockwise,
+            Rotate::Half,
+        ];
+        let rotations = rotations.into_iter().filter_map(|rotate| {
+            let rotated = falling.rotated(rotate, heap, grid_width)?;
+            Some((Step::Rotate(rotate), rotated))
+        });
+
+        for (step, mut next) in moves.chain(rotations).collect::<Vec<_>>() {
+            next.from = Some((idx, step));
+            if step == Step::Move(Motion::HardDrop) {
+                lock(&next, found.len());
+                found.push(next);
+                continue;
+            }
+
+            next.inputs += 1;
+            let key = next.key();
+            if best.get(&key).is_some_and(|&best| best <= next.inputs) {
+                continue;
+            }
+            best.insert(key, next.inputs);
+            queue.push(Reverse((next.inputs, found.len())));
+            found.push(next);
+        }
+    }
+
+    locked
+        .into_iter()
+        .map(|((_, spin, t_spin), (inputs, idx))| {
+            let falling = &found[idx];
+            let mut path = Vec::new();
+            let mut from = falling.from;
+            while let Some((idx, step)) = from {
+                path.push(step);
+                from = found[idx].from;
+            }
+            path.reverse();
+            Placement {
+                blocks: falling.blocks.clone(),
+                spin,
+                t_spin,
+                path,
+                inputs,
+            }
+        })
+        .collect()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+
+    const SIZE: GridSize =
+        GridSize { width: 10, height: 20, hidden_height: 4 };
+
+    // A heap from rows drawn from the top down, as puzzles are
+    fn heap(rows: &[&str]) -> Heap {
+        let mut heap = Heap::new(SIZE);
+        for (y, row) in rows.iter().rev().enumerate() {
+            for (x, cell) in row.chars().enumerate() {
+                if cell == '#' {
+                    heap.blocks[x + y * SIZE.width as usize] =
+                        HeapEntry::Occupied
+                    ;
+                }
+            }
+        }
+        heap
+    }
+
+    fn blocks(placement: &Placement) -> Vec<(i16, i16)> {
+        let mut blocks = placement.blocks
+            .iter()
+            .map(|pos| (pos.x, pos.y))
+            .collect::<Vec<_>>()
+        ;
+        blocks.sort_unstable();
+        blocks
+    }
+
+    #[test]
+    fn pieces_drop_into_every_column_and_orientation() {
+        let heap = Heap::new(SIZE);
+        let counts = PieceKind::ALL.map(|kind| {
+            placements(&heap, kind, SIZE)
+                .iter()
+                .filter(|placement| !placement.spin)
+                .count()
+        });
+        // I, O, T, S, Z, L and J; S, Z and I look the same turned around
+        assert_eq!(counts, [17, 9, 34, 17, 17, 34, 34]);
+    }
+
+    #[test]
+    fn t_pieces_spin_into_t_slots() {
+        let heap = heap(&[
+            ".#........",
+            "#...######",
+            "##.#######",
+        ]);
+        let t_spin_double = placements(&heap, PieceKind::T, SIZE)
+            .into_iter()
+            .find(|placement| {
+                blocks(placement) == [(1, 1), (2, 0), (2, 1), (3, 1)]
+            })
+            .expect("no way into the slot")
+        ;
+        assert!(t_spin_double.spin);
+        assert_eq!(t_spin_double.t_spin, Some(TSpin::Full));
+        assert!(matches!(
+            t_spin_double.path.iter().rev().nth(1),
+            Some(Step::Rotate(_)),
+        ));
+    }
+
+    #[test]
+    fn pieces_tuck_under_overhangs() {
+        let heap = heap(&[
+            "###.......",
+            "..........",
+            "..........",
+        ]);
+        let tuck = placements(&heap, PieceKind::O, SIZE)
+            .into_iter()
+            .find(|placement| {
+                blocks(placement) == [(0, 0), (0, 1), (1, 0), (1, 1)]
+            })
+            .expect("no way under the overhang")
+        ;
+        assert!(!tuck.spin);
+        assert!(matches!(tuck.path[..], [
+            Step::Move(Motion::SoftDrop(_)),
+            Step::Move(Motion::DasLeft),
+            Step::Move(Motion::HardDrop),
+        ]));
+        assert_eq!(tuck.inputs, 2);
+    }
+}
@@ -0,0 +1,180 @@
+use serde::{Deserialize, Serialize};
+use ::core::fmt;
+use ::std::error::Error;
+use ::std::fs;
+use ::std::path::Path;
+use crate::grid::{GridPos, GridSize};
+use crate::heap::{Heap, HeapEntry, LineClearEvent};
+use crate::piece::PieceKind;
+use crate::rotation::TSpin;
+use crate::stats::ClearType;
+
+
+// bump whenever older puzzle files could no longer be read in the same way
+pub const PUZZLE_VERSION: u32 = 1;
+
+// A set scenario: the board to start from, the pieces to play and what to
+// do with them
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Puzzle {
+    pub version: u32,
+    // rows of the board from the top down, with '.' for an empty cell and
+    // '#' for a filled one; the width of the rows is the width of the grid
+    pub board: Vec<String>,
+    pub queue: Vec<PieceKind>,
+    pub goal: PuzzleGoal,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PuzzleGoal {
+    // clear this many lines in all
+    Lines(u32),
+    PerfectClear,
+    // a T-spin clearing this many lines at once, e.g. 2 for a T-spin double
+    TSpin(u8),
+}
+
+impl PuzzleGoal {
+    // Whether the goal has been achieved by the given clear, after which
+    // that many lines have been cleared in all
+    pub fn reached(
+        &self,
+        clear: &LineClearEvent,
+        lines: u32,
+        perfect_clear: bool,
+    ) -> bool {
+        match *self {
+            Self::Lines(goal) => lines >= goal,
+            Self::PerfectClear => perfect_clear,
+            Self::TSpin(goal) => {
+                clear.t_spin == Some(TSpin::Full) && clear.lines == goal
+            },
+        }
+    }
+}
+
+impl fmt::Display for PuzzleGoal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Self::Lines(lines) => write!(f, "Clear {lines} lines"),
+            Self::PerfectClear => write!(f, "Perfect clear"),
+            Self::TSpin(lines) => {
+                let t_spin = ClearType { t_spin: Some(TSpin::Full), lines };
+                write!(f, "{t_spin}")
+            },
+        }
+    }
+}
+
+impl Puzzle {
+    pub fn load(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let puzzle: Self = ron::from_str(&fs::read_to_string(path)?)?;
+        if puzzle.version != PUZZLE_VERSION {
+            return Err(format!(
+                "puzzle has version {}, but only version {} is supported",
+                puzzle.version,
+                PUZZLE_VERSION,
+            ).into());
+        }
+        puzzle.validate()?;
+        Ok(puzzle)
+    }
+
+    fn validate(&self) -> Result<(), Box<dyn Error>> {
+        let width = self.width() as usize;
+        let uneven = self.board
+            .iter()
+            .any(|row| row.chars().count() != width)
+        ;
+        if width < 4 || uneven {
+            return Err(
+                "board rows must all be the same width, at least 4".into()
+            );
+        }
+        let unknown = self.board
+            .iter()
+            .flat_map(|row| row.chars())
+            .find(|&cell| cell != '.' && cell != '#')
+        ;
+        if let Some(cell) = unknown {
+            return Err(format!(
+                "unknown board cell '{cell}' (expected '.' or '#')"
+            ).into());
+        }
+        if self.queue.is_empty() {
+            return Err("queue must hold at least one piece".into());
+        }
+        Ok(())
+    }
+
+    // in cells, which needn't be bytes
+    pub fn width(&self) -> i16 {
+        self.board.first().map_or(0, |row| row.chars().count() as i16)
+    }
+
+    // positions of the filled cells of the board
+    pub fn blocks(&self) -> Vec<GridPos> {
+        self.board
+            .iter()
+            .rev()
+            .enumerate()
+            .flat_map(|(y, row)| {
+                row
+                    .chars()
+                    .enumerate()
+                    .filter(|&(_, cell)| cell == '#')
+                    .map(move |(x, _)| GridPos { x: x as i16, y: y as i16 })
+            })
+            .collect()
+    }
+
+    pub fn heap(&self, grid_size: GridSize) -> Heap {
+        let mut heap = Heap::new(grid_size);
+        for pos in self.blocks() {
+            heap.blocks[(pos.x + pos.y * grid_size.width) as usize] =
+                HeapEntry::Occupied
+            ;
+        }
+        heap
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+
+    fn puzzle(board: &[&str]) -> Puzzle {
+        Puzzle {
+            version: PUZZLE_VERSION,
+            board: board.iter().map(|row| row.to_string()).collect(),
+            queue: vec![PieceKind::I],
+            goal: PuzzleGoal::Lines(1),
+        }
+    }
+
+    #[test]
+    fn boards_are_read_from_the_top_down() {
+        let puzzle = puzzle(&["#...", "..##"]);
+        assert!(puzzle.validate().is_ok());
+        assert_eq!(puzzle.width(), 4);
+        let blocks = puzzle
+            .blocks()
+            .into_iter()
+            .map(|pos| (pos.x, pos.y))
+            .collect::<Vec<_>>()
+        ;
+        assert_eq!(blocks, [(2, 0), (3, 0), (0, 1)]);
+    }
+
+    #[test]
+    fn boards_must_be_even_and_known() {
+        assert!(puzzle(&["####", "###"]).validate().is_err());
+        assert!(puzzle(&["###"]).validate().is_err());
+        assert!(puzzle(&["##x#"]).validate().is_err());
+        // wider in bytes than the other rows, but not in cells
+        let err = puzzle(&["####", "##é#"]).validate().unwrap_err();
+        assert!(err.to_string().starts_with("unknown board cell 'é'"));
+    }
+}
@@ -0,0 +1,181 @@
+use bevy::prelude::*;
+use bevy::app::AppExit;
+use serde::{Deserialize, Serialize};
+use ::std::collections::VecDeque;
+use ::std::error::Error;
+use ::std::fs;
+use ::std::path::{Path, PathBuf};
+use ::std::time::{SystemTime, UNIX_EPOCH};
+use crate::input::Action;
+use crate::ruleset::Ruleset;
+use crate::game::{GameOverEvent, GameOverReason, GameSummary};
+
+
+// bump whenever replays from older versions would no longer play back the same
+pub const REPLAY_VERSION: u32 = 12;
+
+// where recorded games end up, relative to the working directory
+const REPLAY_DIR: &str = "replays";
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Replay {
+    pub version: u32,
+    pub seed: u64,
+    pub ruleset: Ruleset,
+    pub inputs: Vec<InputChange>,
+    // how the game ended, if it did; playing it back has to end the same way
+    #[serde(default)]
+    pub summary: Option<GameSummary>,
+}
+
+// a player's action was pressed or released at the start of the given tick
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub struct InputChange {
+    pub tick: u64,
+    #[serde(default)]
+    pub player: usize,
+    pub action: Action,
+    pub pressed: bool,
+}
+
+impl Replay {
+    pub fn new(seed: u64, ruleset: Ruleset) -> Self {
+        Self {
+            version: REPLAY_VERSION,
+            seed,
+            ruleset,
+            inputs: Vec::new(),
+            summary: None,
+        }
+    }
+
+    pub fn load(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let replay: Self = ron::from_str(&fs::read_to_string(path)?)?;
+        if replay.version != REPLAY_VERSION {
+            return Err(format!(
+                "replay has version {}, but only version {} is supported",
+                replay.version,
+                REPLAY_VERSION,
+            ).into());
+        }
+        Ok(replay)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let pretty = ron::ser::PrettyConfig::default();
+        fs::write(path, ron::ser::to_string_pretty(self, pretty)?)?;
+        Ok(())
+    }
+}
+
+// the game being played, as it is being recorded
+#[derive(Resource)]
+pub struct Recording(Replay);
+
+impl Recording {
+    pub fn new(seed: u64, ruleset: Ruleset) -> Self {
+        Self(Replay::new(seed, ruleset))
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.0.seed
+    }
+
+    pub fn push(&mut self, change: InputChange) {
+        self.0.inputs.push(change);
+    }
+
+    pub fn save(&self) {
+        let path = replay_path(self.0.seed);
+        if let Err(err) = self.0.save(&path) {
+            eprintln!("Couldn't save replay to {}: {err}", path.display());
+        }
+    }
+}
+
+// a recorded game standing in for the keyboard
+#[derive(Resource)]
+pub struct Playback {
+    inputs: VecDeque<InputChange>,
+    summary: Option<GameSummary>,
+}
+
+impl Playback {
+    pub fn new(replay: Replay) -> Self {
+        Self {
+            inputs: replay.inputs.into(),
+            summary: replay.summary,
+        }
+    }
+
+    // all action changes up to and including the given tick, by player
+    pub fn take(&mut self, tick: u64) -> Vec<(usize, Action, bool)> {
+        let mut changes = Vec::new();
+        while let Some(change) = self.inputs
+            .front()
+            .filter(|change| change.tick <= tick)
+        {
+            changes.push((change.player, change.action, change.pressed));
+            self.inputs.pop_front();
+        }
+        changes
+    }
+}
+
+
+// Save a finished game, or check that the game played back ended just like
+// the recorded one did
+pub fn finish_replay(
+    mut commands: Commands,
+    mut game_over_events: EventReader<GameOverEvent>,
+    recording: Option<ResMut<Recording>>,
+    playback: Option<Res<Playback>>,
+) {
+    let Some(game_over) = game_over_events.iter().last() else { return };
+
+    if let Some(mut recording) = recording {
+        // a game cut short over the network has no ending to play back to
+        let cut_short = matches!(
+            game_over.summary.reason,
+            GameOverReason::Disconnected | GameOverReason::Desynced,
+        );
+        if !cut_short {
+            recording.0.summary = Some(game_over.summary.clone());
+        }
+        recording.save();
+        commands.remove_resource::<Recording>();
+    }
+
+    let expected = playback.and_then(|playback| playback.summary.clone());
+    if let Some(expected) = expected.filter(|exp| *exp != game_over.summary) {
+        eprintln!(
+            "Replay diverged from the recorded game: expected {:?}, got {:?}",
+            expected,
+            game_over.summary,
+        );
+    }
+}
+
+pub fn save_replay(
+    exit_events: EventReader<AppExit>,
+    recording: Option<Res<Recording>>,
+) {
+    let Some(recording) = recording else { return };
+    if exit_events.is_empty() {
+        return;
+    }
+
+    recording.save();
+}
+
+fn replay_path(seed: u64) -> PathBuf {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis()
+    ;
+    Path::new(REPLAY_DIR).join(format!("{timestamp}-{seed:016x}.ron"))
+}
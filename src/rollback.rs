@@ -0,0 +1,278 @@
+use bevy::prelude::*;
+use ::std::collections::VecDeque;
+use crate::board::Board;
+use crate::bot::{Bot, Bots};
+use crate::finesse::PieceInputs;
+use crate::garbage::{GarbageRows, PendingGarbage};
+use crate::grid::GridPos;
+use crate::heap::{GarbageEvent, Heap};
+use crate::input::Inputs;
+use crate::level::Level;
+use crate::movement::{
+    GarbageTimer,
+    GravityTimer,
+    LockDelay,
+    MovementXTimer,
+    MovementYTimer,
+};
+use crate::net::NetSession;
+use crate::piece::{
+    ActivePiece,
+    Block,
+    Hold,
+    Origin,
+    PieceQueue,
+    Randomizer,
+    SpawnEvent,
+    spawn_block,
+};
+use crate::score::Score;
+use crate::stats::Stats;
+use crate::tick::GameClock;
+
+
+// The game as a tick is about to run, which is all it takes to run the tick
+// (and every one after it) over again
+struct Snapshot {
+    tick: u64,
+    boards: Vec<BoardSnapshot>,
+    // events from the tick before, which have yet to be handled
+    spawns: Vec<SpawnEvent>,
+    garbage: Vec<GarbageEvent>,
+}
+
+struct BoardSnapshot {
+    board: Entity,
+    piece: (ActivePiece, Origin, Randomizer, Hold),
+    queue: Option<PieceQueue>,
+    heap: (Heap, GarbageRows, PendingGarbage),
+    timers: (
+        GravityTimer,
+        MovementXTimer,
+        MovementYTimer,
+        GarbageTimer,
+        LockDelay,
+    ),
+    tally: (Score, Level, Stats),
+    inputs: (Inputs, PieceInputs),
+    // (position, color, whether it's part of the piece)
+    blocks: Vec<(GridPos, Color, bool)>,
+}
+
+// snapshots of the ticks that the other end's actions aren't known for yet,
+// oldest first
+#[derive(Resource, Default)]
+pub struct Snapshots(VecDeque<Snapshot>);
+
+
+// Snapshot the game before each tick that may have to be run again, once the
+// other end's actions for it turn out to be different than predicted
+pub fn save_snapshot(
+    clock: Res<GameClock>,
+    session: Option<Res<NetSession>>,
+    mut snapshots: ResMut<Snapshots>,
+    spawn_events: Res<Events<SpawnEvent>>,
+    garbage_events: Res<Events<GarbageEvent>>,
+    boards: Query<(
+        Entity,
+        (&ActivePiece, &Origin, &Randomizer, &Hold),
+        Option<&PieceQueue>,
+        (&Heap, &GarbageRows, &PendingGarbage),
+        (
+            &GravityTimer,
+            &MovementXTimer,
+            &MovementYTimer,
+            &GarbageTimer,
+            &LockDelay,
+        ),
+        (&Score, &Level, &Stats),
+        (&Inputs, &PieceInputs),
+    ), With<Board>>,
+    blocks: Query<(&Parent, &GridPos, &Sprite, Option<&Block>)>,
+) {
+    let Some(session) = session else { return };
+
+    // the tick about to run may have been run before
+    let tick = clock.tick;
+    snapshots.0.retain(|snapshot| {
+        !session.confirmed(snapshot.tick) && snapshot.tick < tick
+    });
+
+    let boards = boards
+        .iter()
+        .map(|(board, piece, queue, heap, timers, tally, inputs)| {
+            let blocks = blocks
+                .iter()
+                .filter(|(parent, ..)| parent.get() == board)
+                .map(|(_, &pos, sprite, block)| {
+                    (pos, sprite.color, block.is_some())
+                })
+                .collect()
+            ;
+            BoardSnapshot {
+                board,
+                piece: (*piece.0, *piece.1, piece.2.clone(), *piece.3),
+                queue: queue.cloned(),
+                heap: (heap.0.clone(), heap.1.clone(), heap.2.clone()),
+                timers: (
+                    timers.0.clone(),
+                    timers.1.clone(),
+                    timers.2.clone(),
+                    timers.3.clone(),
+                    timers.4.clone(),
+                ),
+                tally: (tally.0.clone(), tally.1.clone(), tally.2.clone()),
+                inputs: (inputs.0.clone(), inputs.1.clone()),
+                blocks,
+            }
+        })
+        .collect()
+    ;
+    snapshots.0.push_back(Snapshot {
+        tick,
+        boards,
+        spawns: spawn_events.iter_current_update_events().cloned().collect(),
+        garbage: garbage_events
+            .iter_current_update_events()
+            .cloned()
+            .collect(),
+    });
+}
+
+// Go back to the first tick that was run on a wrong prediction of the other
+// end's actions, so that it and every tick since are run again
+pub fn roll_back(
+    mut commands: Commands,
+    session: Option<ResMut<NetSession>>,
+    mut clock: ResMut<GameClock>,
+    bots: Option<ResMut<Bots>>,
+    snapshots: Res<Snapshots>,
+    mut spawn_events: ResMut<Events<SpawnEvent>>,
+    mut garbage_events: ResMut<Events<GarbageEvent>>,
+    blocks: Query<(Entity, &Parent), With<GridPos>>,
+) {
+    let Some(mut session) = session else { return };
+    let Some(tick) = session.take_rollback() else { return };
+    let Some(snapshot) = snapshots.0
+        .iter()
+        .find(|snapshot| snapshot.tick == tick)
+    else {
+        // there's no telling what the game should look like
+        session.lose_sync();
+        return;
+    };
+
+    for (block, parent) in blocks.iter() {
+        if snapshot.boards.iter().any(|board| board.board == parent.get()) {
+            commands.entity(block).despawn_recursive();
+        }
+    }
+    for board in &snapshot.boards {
+        commands.entity(board.board).insert((
+            board.piece.clone(),
+            board.heap.clone(),
+            board.timers.clone(),
+            board.tally.clone(),
+            board.inputs.clone(),
+        ));
+        match &board.queue {
+            Some(queue) => {
+                commands.entity(board.board).insert(queue.clone());
+            },
+            None => {
+                commands.entity(board.board).remove::<PieceQueue>();
+            },
+        }
+        for &(pos, color, active) in &board.blocks {
+            let mut block = spawn_block(&mut commands, board.board, pos, color);
+            if active {
+                block.insert(Block);
+            }
+        }
+    }
+
+    // whatever came of the ticks being undone is undone too
+    spawn_events.clear();
+    spawn_events.extend(snapshot.spawns.iter().cloned());
+    garbage_events.clear();
+    garbage_events.extend(snapshot.garbage.iter().cloned());
+
+    // bots planned for pieces where they were before
+    if let Some(mut bots) = bots {
+        bots.playing.values_mut().for_each(Bot::replan);
+    }
+
+    clock.rewind(tick - 1);
+    session.rewind(tick);
+}
+
+
+#[cfg(test)]
+mod tests {
+    use bevy::utils::Duration;
+    use crate::grid::GridSize;
+    use crate::heap::HeapEntry;
+    use crate::piece::{OriginMode, PieceKind};
+    use super::*;
+
+
+    const SIZE: GridSize = GridSize { width: 4, height: 3, hidden_height: 1 };
+
+    fn spawn_board(world: &mut World) -> Entity {
+        let duration = Duration::from_millis(500);
+        world.spawn((
+            (
+                Board { player: 0 },
+                ActivePiece {
+                    kind: PieceKind::T,
+                    rotation: 0,
+                    last_kick: None,
+                },
+                Origin {
+                    pos: GridPos { x: 1, y: 2 },
+                    mode: OriginMode::PointCentered,
+                },
+                Randomizer::new(0),
+                Hold::default(),
+            ),
+            (Heap::new(SIZE), GarbageRows(0), PendingGarbage::default()),
+            (
+                GravityTimer::new(duration),
+                MovementXTimer::new(duration),
+                MovementYTimer::new(duration),
+                GarbageTimer::new(duration),
+                LockDelay::new(duration),
+            ),
+            (Score::default(), Level(1), Stats::default()),
+            (Inputs::new(), PieceInputs::default()),
+        )).id()
+    }
+
+    #[test]
+    fn rolling_back_restores_the_snapshot() {
+        let mut world = World::new();
+        world.init_resource::<Snapshots>();
+        world.init_resource::<Events<SpawnEvent>>();
+        world.init_resource::<Events<GarbageEvent>>();
+        world.init_resource::<GameClock>();
+        world.resource_mut::<GameClock>().tick = 10;
+        world.insert_resource(NetSession::rolling_back(10));
+        let board = spawn_board(&mut world);
+        SystemStage::single(save_snapshot).run(&mut world);
+
+        // a couple of ticks go by on a wrong prediction
+        world.resource_mut::<GameClock>().tick = 12;
+        world.get_mut::<Heap>(board).unwrap().blocks[0] = HeapEntry::Occupied;
+        world.get_mut::<Score>(board).unwrap().points = 100;
+        SystemStage::single(roll_back).run(&mut world);
+
+        let heap = world.get::<Heap>(board).unwrap();
+        assert!(heap.blocks.iter().all(|entry| {
+            matches!(entry, HeapEntry::Vacant)
+        }));
+        assert_eq!(world.get::<Score>(board).unwrap().points, 0);
+        // the tick rolled back to is the next one to run
+        assert_eq!(world.resource::<GameClock>().tick, 9);
+        assert!(world.resource_mut::<NetSession>().take_rollback().is_none());
+    }
+}
@@ -1,64 +1,176 @@
 use bevy::prelude::*;
 use crate::grid::{GridSize, GridPos};
-use crate::piece::{Block, Origin, OriginMode};
+use crate::piece::{ActivePiece, Block, Origin, OriginMode, PieceKind};
 use crate::movement::{MoveNeutral, can_move};
 use crate::heap::Heap;
-use crate::input::{Input, Inputs};
+use crate::input::{Action, Inputs};
+use crate::ruleset::Ruleset;
 use ::core::iter;
 
 
-#[derive(Copy, Clone, PartialEq)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum Rotate {
     Clockwise,
     Counterclockwise,
+    Half,
 }
 
+impl Rotate {
+    // in quarter turns clockwise
+    pub fn turns(self) -> u8 {
+        match self {
+            Self::Clockwise => 1,
+            Self::Half => 2,
+            Self::Counterclockwise => 3,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum TSpin {
+    Mini,
+    Full,
+}
+
+// relative translations from one kick to the next
+// (according to the wiki ¯\_(ツ)_/¯) T-spins ──────┬───┬
+const KICKS: [(i16, i16); 5] = [(1, 0), (1, 0), (-3, 0), (-1, 0), (1, -2)];
+
 
 pub fn rotation(
-    heap: Res<Heap>,
-    grid_size: Res<GridSize>,
-    origin: Res<Origin>,
-    inputs: Res<Inputs>,
-    mut block_pos: Query<&mut GridPos, With<Block>>,
+    ruleset: Res<Ruleset>,
+    mut boards: Query<(
+        Entity,
+        &GridSize,
+        &Heap,
+        &Inputs,
+        &mut Origin,
+        &mut ActivePiece,
+    )>,
+    mut blocks: Query<(&Parent, &mut GridPos), With<Block>>,
 ) {
-    let grid_width = grid_size.width;
+    for (
+        board,
+        grid_size,
+        heap,
+        inputs,
+        mut origin,
+        mut piece,
+    ) in boards.iter_mut() {
+        // get rotation input
+        let rotate = match inputs.resolve_socd(
+            ruleset.socd_mode,
+            Inputs::just_pressed,
+            Action::RotateClockwise,
+            Action::RotateCounterclockwise,
+        ) {
+            Some(Action::RotateClockwise) => Rotate::Clockwise,
+            Some(_) => Rotate::Counterclockwise,
+            None if inputs.just_pressed(Action::Rotate180) => Rotate::Half,
+            None => continue,
+        };
 
-    // get rotation input
-    let clkw = inputs.get_action_state(Input::ClkwJustPressed);
-    let cclw = inputs.get_action_state(Input::CclwJustPressed);
-    let rotate = match (clkw, cclw) {
-        (true, true) | (false, false) => return,
-        (true, false) => Rotate::Clockwise,
-        (false, true) => Rotate::Counterclockwise,
-    };
+        let mut block_pos = blocks
+            .iter_mut()
+            .filter(|(parent, _)| parent.get() == board)
+            .map(|(_, pos)| pos)
+            .collect::<Vec<_>>()
+        ;
+        // a piece spawned this tick only shows up once the tick is over
+        if block_pos.is_empty() {
+            continue;
+        }
+        let positions = block_pos
+            .iter()
+            .map(|pos| **pos)
+            .collect::<Vec<_>>()
+        ;
 
-    let mut block_pos = block_pos.iter_mut().collect::<Vec<_>>();
-    // store original positions just in case rotation needs to be reverted
-    let prev_pos = block_pos.iter().map(|pos| **pos).collect::<Vec<_>>();
+        let Some((rotated, rotated_origin, kick)) =
+            rotate_piece(&positions, *origin, rotate, heap, grid_size.width)
+        else {
+            continue;
+        };
 
-    basic_rotation(&mut block_pos, rotate, *origin);
+        iter::zip(&mut block_pos, rotated)
+            .for_each(|(pos, rotated)| **pos = rotated)
+        ;
+        *origin = rotated_origin;
+        piece.rotation = (piece.rotation + rotate.turns()) % 4;
+        piece.last_kick = Some(kick);
+    }
+}
+
+// The piece's blocks and origin once rotated, and kicked if need be, along
+// with the kick that was taken; none if the piece can't rotate at all
+pub fn rotate_piece(
+    blocks: &[GridPos],
+    origin: Origin,
+    rotate: Rotate,
+    heap: &Heap,
+    grid_width: i16,
+) -> Option<(Vec<GridPos>, Origin, usize)> {
+    let mut rotated = blocks.to_vec();
+    let mut origin = origin;
+
+    basic_rotation(&mut rotated, rotate, origin);
 
     // wall kicks
-    if !can_move(&block_pos, grid_width, MoveNeutral, &heap) {
-        // relative translations from one kick to the next
-        // (according to the wiki ¯\_(ツ)_/¯) T-spins ──────┬───┬
-        for try_move in [(1, 0), (1, 0), (-3, 0), (-1, 0), (1, -2)] {
-            block_pos.iter_mut().for_each(|pos| **pos += try_move);
-            if can_move(&block_pos, grid_width, MoveNeutral, &heap) {
-                // kick was successful
-                return;
-            }
+    for kick in 0..=KICKS.len() {
+        if kick > 0 {
+            let try_move = KICKS[kick - 1];
+            rotated.iter_mut().for_each(|pos| *pos += try_move);
+            origin.pos += try_move;
         }
 
-        // revert rotation
-        iter::zip(&mut block_pos, &prev_pos)
-            .for_each(|(pos, prev_pos)| **pos = *prev_pos)
-        ;
+        if can_move(rotated.iter(), grid_width, MoveNeutral, heap) {
+            return Some((rotated, origin, kick));
+        }
+    }
+
+    None
+}
+
+// Whether the piece, as it locks, counts as having T-spun, by the 3-corner
+// rule: three of the four cells diagonal to the T's center must be occupied
+pub fn t_spin(
+    piece: &ActivePiece,
+    origin: &Origin,
+    heap: &Heap,
+    grid_width: i16,
+) -> Option<TSpin> {
+    if piece.kind != PieceKind::T {
+        return None;
+    }
+    // the piece has to have been rotated into place
+    let kick = piece.last_kick?;
+
+    let corners = [(-1, 1), (1, 1), (1, -1), (-1, -1)];
+    let occupied = corners
+        .map(|offset| heap.occupied(origin.pos + offset, grid_width))
+    ;
+    // the two corners on the side the T points towards, which is up when it
+    // spawns
+    let front = piece.rotation as usize;
+    let front_occupied = [front, (front + 1) % 4]
+        .iter()
+        .filter(|&&corner| occupied[corner])
+        .count()
+    ;
+    let total_occupied = occupied.iter().filter(|&&occupied| occupied).count();
+
+    if total_occupied < 3 {
+        None
+    } else if front_occupied == 2 || kick == KICKS.len() {
+        // the last kick can only be taken by "threading" the T into place
+        Some(TSpin::Full)
+    } else {
+        Some(TSpin::Mini)
     }
 }
 
 fn basic_rotation(
-    block_pos: &mut Vec<Mut<GridPos>>,
+    block_pos: &mut [GridPos],
     rotate: Rotate,
     origin: Origin
 ) {
@@ -68,6 +180,12 @@ fn basic_rotation(
     let origin_x = origin.pos.x;
     let origin_y = origin.pos.y;
 
+    if rotate == Rotate::Half {
+        basic_rotation(block_pos, Rotate::Clockwise, origin);
+        basic_rotation(block_pos, Rotate::Clockwise, origin);
+        return;
+    }
+
     for pos in block_pos {
         match rotate {
             Rotate::Clockwise => {
@@ -92,7 +210,8 @@ fn basic_rotation(
                 pos.x = -norm_y;
                 pos.y = norm_x;
             },
+            Rotate::Half => unreachable!(),
         }
-        **pos += (origin_x, origin_y);
+        *pos += (origin_x, origin_y);
     }
 }
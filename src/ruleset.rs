@@ -0,0 +1,92 @@
+use bevy::prelude::Resource;
+use bevy::utils::Duration;
+use serde::{Deserialize, Serialize};
+use crate::attack::AttackTable;
+use crate::grid::GridSize;
+use crate::input::SocdMode;
+use crate::game::TopOut;
+use crate::level::{GravityCurve, LevelGoal};
+use crate::mode::GameMode;
+use crate::puzzle::Puzzle;
+
+
+// Everything about how a game plays that isn't down to the player; a game is
+// fully determined by its ruleset, its seed and its inputs
+#[derive(Debug, Clone, Resource, Serialize, Deserialize)]
+pub struct Ruleset {
+    pub mode: GameMode,
+    pub grid_size: GridSize,
+    pub socd_mode: SocdMode,
+    // pieces shown coming up after the current one
+    pub preview: usize,
+    pub start_level: u32,
+    pub level_goal: LevelGoal,
+    pub gravity: GravityCurve,
+    // time between each row moved while soft dropping
+    pub soft_drop: Duration,
+    // how long left or right must be held before the piece starts shifting
+    pub auto_shift_delay: Duration,
+    // time between each column moved while shifting
+    pub auto_repeat: Duration,
+    // how long a piece can rest on the heap before it locks
+    pub lock_delay: Duration,
+    // how many times moving or rotating a resting piece can put off its
+    // locking, until it gets any lower
+    pub lock_resets: u32,
+    pub top_out: TopOutRules,
+    // garbage sent to opponents for each way of clearing
+    pub attack: AttackTable,
+    // time garbage spends on its way to an opponent, during which their own
+    // clears can cancel it
+    pub garbage_delay: Duration,
+    // board, pieces and goal of the puzzle being played, if any
+    pub puzzle: Option<Puzzle>,
+}
+
+// Which top-outs end the game; blocks that would lock outside of the heap
+// altogether always do, as do blocks pushed out of it by garbage
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct TopOutRules {
+    pub block_out: bool,
+    pub lock_out: bool,
+    pub partial_lock_out: bool,
+}
+
+impl TopOutRules {
+    pub fn enabled(&self, top_out: TopOut) -> bool {
+        match top_out {
+            TopOut::BlockOut => self.block_out,
+            TopOut::LockOut => self.lock_out,
+            TopOut::PartialLockOut => self.partial_lock_out,
+            TopOut::GarbageOut => true,
+        }
+    }
+}
+
+impl Default for Ruleset {
+    fn default() -> Self {
+        Self {
+            mode: GameMode::default(),
+            grid_size: GridSize { width: 15, height: 25, hidden_height: 20 },
+            socd_mode: SocdMode::default(),
+            preview: 5,
+            start_level: 1,
+            level_goal: LevelGoal::Fixed(10),
+            gravity: GravityCurve::Guideline,
+            soft_drop: Duration::from_millis(80),
+            auto_shift_delay: Duration::from_millis(150),
+            auto_repeat: Duration::from_millis(80),
+            lock_delay: Duration::from_millis(500),
+            lock_resets: 15,
+            // as per the guideline
+            top_out: TopOutRules {
+                block_out: true,
+                lock_out: true,
+                partial_lock_out: false,
+            },
+            attack: AttackTable::default(),
+            garbage_delay: Duration::from_millis(500),
+            puzzle: None,
+        }
+    }
+}
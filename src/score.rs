@@ -0,0 +1,259 @@
+use bevy::prelude::*;
+use ::core::fmt;
+use crate::heap::{LineClearEvent, PerfectClearEvent};
+use crate::level::Level;
+use crate::movement::DropEvent;
+use crate::rotation::TSpin;
+
+
+#[derive(Clone, Component, Default)]
+pub struct Score {
+    pub points: u64,
+    pub lines: u32,
+    // pieces in a row that have cleared lines, minus one
+    combo: Option<u32>,
+    // the last clear was a difficult one (a tetris or a T-spin)
+    back_to_back: bool,
+}
+
+const LINE_NAMES: [&str; 5] = ["", "Single", "Double", "Triple", "Tetris"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScoreKind {
+    SoftDrop { rows: u32 },
+    HardDrop { rows: u32 },
+    Clear { lines: u8, t_spin: Option<TSpin>, back_to_back: bool },
+    Combo(u32),
+    PerfectClear { lines: u8, back_to_back: bool },
+}
+
+impl fmt::Display for ScoreKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Self::SoftDrop { .. } => write!(f, "Soft drop"),
+            Self::HardDrop { .. } => write!(f, "Hard drop"),
+            Self::Clear { lines, t_spin, back_to_back } => {
+                if back_to_back {
+                    write!(f, "Back-to-back ")?;
+                }
+                let lines = lines.min(4) as usize;
+                match t_spin {
+                    Some(TSpin::Mini) => write!(f, "Mini T-spin")?,
+                    Some(TSpin::Full) => write!(f, "T-spin")?,
+                    None => return write!(f, "{}", LINE_NAMES[lines]),
+                }
+                if lines > 0 {
+                    write!(f, " {}", LINE_NAMES[lines].to_lowercase())?;
+                }
+                Ok(())
+            },
+            Self::Combo(combo) => write!(f, "{combo} combo"),
+            Self::PerfectClear { back_to_back: true, .. } => {
+                write!(f, "Back-to-back perfect clear")
+            },
+            Self::PerfectClear { .. } => write!(f, "Perfect clear"),
+        }
+    }
+}
+
+impl Score {
+    // Points that a locked piece scores for the lines it cleared (if any),
+    // each way they were scored, keeping track of back-to-back clears and
+    // combos along the way
+    fn clear(
+        &mut self,
+        lines: u8,
+        t_spin: Option<TSpin>,
+        level: u64,
+    ) -> Vec<(ScoreKind, u64)> {
+        if lines == 0 {
+            self.combo = None;
+        } else {
+            self.lines += lines as u32;
+            self.combo = Some(self.combo.map_or(0, |combo| combo + 1));
+        }
+
+        let difficult = lines > 0 && (lines >= 4 || t_spin.is_some());
+        let back_to_back = difficult && self.back_to_back;
+        if lines > 0 {
+            self.back_to_back = difficult;
+        }
+
+        let mut points = match (t_spin, lines) {
+            (None, 0) => 0,
+            (None, 1) => 100,
+            (None, 2) => 300,
+            (None, 3) => 500,
+            (None, _) => 800,
+            (Some(TSpin::Mini), 0) => 100,
+            (Some(TSpin::Mini), 1) => 200,
+            (Some(TSpin::Mini), _) => 400,
+            (Some(TSpin::Full), 0) => 400,
+            (Some(TSpin::Full), 1) => 800,
+            (Some(TSpin::Full), 2) => 1200,
+            (Some(TSpin::Full), _) => 1600,
+        };
+        if back_to_back {
+            points = points * 3 / 2;
+        }
+
+        let mut awards = Vec::new();
+        if points > 0 {
+            let kind = ScoreKind::Clear { lines, t_spin, back_to_back };
+            awards.push((kind, points * level));
+        }
+        if let Some(combo) = self.combo.filter(|&combo| combo > 0) {
+            awards.push((ScoreKind::Combo(combo), 50 * combo as u64 * level));
+        }
+        awards
+    }
+}
+
+// Points for a perfect clear of the given number of lines, before the level
+// is taken into account
+fn perfect_clear_points(lines: u8, back_to_back_tetris: bool) -> u64 {
+    match lines {
+        1 => 800,
+        2 => 1200,
+        3 => 1800,
+        _ if back_to_back_tetris => 3200,
+        _ => 2000,
+    }
+}
+
+// points were awarded on a board
+pub struct ScoreEvent {
+    pub board: Entity,
+    pub kind: ScoreKind,
+    pub points: u64,
+}
+
+
+// Award points as per the guideline
+pub fn score(
+    mut drop_events: EventReader<DropEvent>,
+    mut clear_events: EventReader<LineClearEvent>,
+    mut perfect_clear_events: EventReader<PerfectClearEvent>,
+    mut score_notify: EventWriter<ScoreEvent>,
+    mut boards: Query<(&Level, &mut Score)>,
+) {
+    let mut award = |board, score: &mut Score, kind, points| {
+        score.points += points;
+        score_notify.send(ScoreEvent { board, kind, points });
+    };
+
+    for drop in drop_events.iter() {
+        let board = drop.board;
+        let Ok((_, mut score)) = boards.get_mut(board) else { continue };
+
+        let (kind, points_per_row) = if drop.hard {
+            (ScoreKind::HardDrop { rows: drop.rows }, 2)
+        } else {
+            (ScoreKind::SoftDrop { rows: drop.rows }, 1)
+        };
+        award(board, &mut score, kind, points_per_row * drop.rows as u64);
+    }
+
+    // a back-to-back tetris makes for a more valuable perfect clear
+    let mut back_to_back_tetrises = Vec::new();
+    for clear in clear_events.iter() {
+        let board = clear.board;
+        let Ok((level, mut score)) = boards.get_mut(board) else { continue };
+        let awards = score.clear(clear.lines, clear.t_spin, level.0 as u64);
+        for (kind, points) in awards {
+            let back_to_back_tetris = matches!(
+                kind,
+                ScoreKind::Clear { lines: 4.., back_to_back: true, .. },
+            );
+            if back_to_back_tetris {
+                back_to_back_tetrises.push(board);
+            }
+            award(board, &mut score, kind, points);
+        }
+    }
+
+    for &PerfectClearEvent { board, lines } in perfect_clear_events.iter() {
+        let Ok((level, mut score)) = boards.get_mut(board) else { continue };
+        let back_to_back_tetris = back_to_back_tetrises.contains(&board);
+
+        let points = perfect_clear_points(lines, back_to_back_tetris);
+        let kind = ScoreKind::PerfectClear {
+            lines,
+            back_to_back: back_to_back_tetris,
+        };
+        award(board, &mut score, kind, points * level.0 as u64);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+
+    #[test]
+    fn clears_score_by_lines_and_t_spin_times_level() {
+        let mut score = Score::default();
+        assert_eq!(score.clear(0, None, 3), []);
+        assert_eq!(score.clear(3, None, 3), [(
+            ScoreKind::Clear { lines: 3, t_spin: None, back_to_back: false },
+            1500,
+        )]);
+
+        let mut score = Score::default();
+        assert_eq!(score.clear(0, Some(TSpin::Full), 2), [(
+            ScoreKind::Clear {
+                lines: 0,
+                t_spin: Some(TSpin::Full),
+                back_to_back: false,
+            },
+            800,
+        )]);
+        assert_eq!(score.lines, 0);
+    }
+
+    #[test]
+    fn difficult_clears_in_a_row_are_back_to_back() {
+        let mut score = Score::default();
+        score.clear(4, None, 1);
+        score.clear(0, None, 1);
+        let awards = score.clear(2, Some(TSpin::Full), 1);
+        assert_eq!(awards[0], (
+            ScoreKind::Clear {
+                lines: 2,
+                t_spin: Some(TSpin::Full),
+                back_to_back: true,
+            },
+            1800,
+        ));
+
+        // an easy clear breaks the chain, but a T-spin without lines doesn't
+        score.clear(0, Some(TSpin::Mini), 1);
+        score.clear(1, None, 1);
+        let awards = score.clear(4, None, 1);
+        assert_eq!(awards[0], (
+            ScoreKind::Clear { lines: 4, t_spin: None, back_to_back: false },
+            800,
+        ));
+    }
+
+    #[test]
+    fn combos_count_clears_in_a_row() {
+        let mut score = Score::default();
+        assert_eq!(score.clear(1, None, 2).len(), 1);
+        assert_eq!(score.clear(1, None, 2)[1], (ScoreKind::Combo(1), 100));
+        assert_eq!(score.clear(2, None, 2)[1], (ScoreKind::Combo(2), 200));
+        assert_eq!(score.lines, 4);
+
+        // a piece that clears nothing ends the combo
+        score.clear(0, None, 2);
+        assert_eq!(score.clear(1, None, 2).len(), 1);
+    }
+
+    #[test]
+    fn back_to_back_tetris_perfect_clears_are_worth_more() {
+        assert_eq!(perfect_clear_points(2, false), 1200);
+        assert_eq!(perfect_clear_points(4, false), 2000);
+        assert_eq!(perfect_clear_points(4, true), 3200);
+    }
+}
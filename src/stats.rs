@@ -0,0 +1,108 @@
+use bevy::prelude::*;
+use ::core::fmt;
+use ::std::collections::BTreeMap;
+use crate::heap::{LineClearEvent, LockEvent};
+use crate::input::{Action, Inputs};
+use crate::piece::PieceKind;
+use crate::rotation::TSpin;
+use crate::ruleset::Ruleset;
+use crate::score::{ScoreEvent, ScoreKind};
+use crate::tick::GameClock;
+
+
+// How the game in progress (or the last one) has been played on a board
+#[derive(Clone, Component, Default)]
+pub struct Stats {
+    pub pieces: u32,
+    pub lines: u32,
+    // presses of gameplay actions
+    pub keys: u32,
+    // garbage lines the clears sent, or would have sent, to an opponent
+    pub attack: u32,
+    // pieces placed with more inputs than they needed
+    pub finesse_faults: u32,
+    pub piece_counts: BTreeMap<PieceKind, u32>,
+    // T-spins that cleared no lines count too
+    pub clear_counts: BTreeMap<ClearType, u32>,
+}
+
+impl Stats {
+    pub fn pieces_per_second(&self, clock: &GameClock) -> f32 {
+        per(self.pieces, clock.time().as_secs_f32())
+    }
+
+    pub fn keys_per_piece(&self) -> f32 {
+        per(self.keys, self.pieces as f32)
+    }
+
+    pub fn attack_per_minute(&self, clock: &GameClock) -> f32 {
+        per(self.attack, clock.time().as_secs_f32() / 60.0)
+    }
+}
+
+// e.g. "Triple" or "T-spin single"
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ClearType {
+    pub t_spin: Option<TSpin>,
+    pub lines: u8,
+}
+
+impl fmt::Display for ClearType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kind = ScoreKind::Clear {
+            lines: self.lines,
+            t_spin: self.t_spin,
+            back_to_back: false,
+        };
+        write!(f, "{kind}")
+    }
+}
+
+
+pub fn update_stats(
+    ruleset: Res<Ruleset>,
+    mut lock_events: EventReader<LockEvent>,
+    mut clear_events: EventReader<LineClearEvent>,
+    mut score_events: EventReader<ScoreEvent>,
+    mut boards: Query<(&Inputs, &mut Stats)>,
+) {
+    for (inputs, mut stats) in boards.iter_mut() {
+        stats.keys += Action::ALL
+            .into_iter()
+            .filter(|&action| !action.is_menu() && inputs.just_pressed(action))
+            .count() as u32
+        ;
+    }
+
+    for lock in lock_events.iter() {
+        let Ok((_, mut stats)) = boards.get_mut(lock.board) else { continue };
+        stats.pieces += 1;
+        *stats.piece_counts.entry(lock.kind).or_default() += 1;
+    }
+
+    for clear in clear_events.iter() {
+        let Ok((_, mut stats)) = boards.get_mut(clear.board) else { continue };
+        stats.lines += clear.lines as u32;
+        if clear.lines > 0 || clear.t_spin.is_some() {
+            let clear_type = ClearType {
+                t_spin: clear.t_spin,
+                lines: clear.lines,
+            };
+            *stats.clear_counts.entry(clear_type).or_default() += 1;
+        }
+    }
+
+    for event in score_events.iter() {
+        let Ok((_, mut stats)) = boards.get_mut(event.board) else { continue };
+        stats.attack += ruleset.attack.attack(event.kind);
+    }
+}
+
+// rate that's zero rather than undefined before anything has happened
+fn per(count: u32, unit: f32) -> f32 {
+    if unit > 0.0 {
+        count as f32 / unit
+    } else {
+        0.0
+    }
+}
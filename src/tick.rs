@@ -0,0 +1,124 @@
+use bevy::prelude::*;
+use bevy::ecs::{event::Event, schedule::ShouldRun};
+use bevy::utils::Duration;
+use crate::game::GameState;
+use crate::net::NetSession;
+
+
+// The simulation advances in fixed steps so that a game only depends on its
+// seed, its ruleset and its inputs, no matter the frame rate
+pub const TICK: Duration = Duration::from_nanos(1_000_000_000 / 60);
+
+// don't try to catch up on more than this after a stall
+const MAX_LAG: Duration = Duration::from_millis(250);
+
+// stage running the game logic, once per elapsed tick
+#[derive(StageLabel)]
+pub struct GameTick;
+
+// runs first thing within every tick
+#[derive(SystemLabel)]
+pub struct BeginTick;
+
+#[derive(Resource, Default)]
+pub struct GameClock {
+    // number of the current (or last finished) tick
+    pub tick: u64,
+    // time elapsed but not yet simulated
+    lag: Duration,
+    // ticks that were rolled back, to be simulated again right away
+    rewound: u64,
+}
+
+impl GameClock {
+    // game time simulated so far
+    pub fn time(&self) -> Duration {
+        TICK * self.tick as u32
+    }
+
+    // Go back to the end of the given tick, to simulate the ticks since then
+    // over again
+    pub fn rewind(&mut self, tick: u64) {
+        let latest = self.tick + self.rewound;
+        self.tick = tick;
+        self.rewound = latest - tick;
+    }
+}
+
+
+pub trait TickApp {
+    // Like `App::add_event`, but events are kept around for one tick rather
+    // than one frame, so that they can't be missed when no tick happens to
+    // run during a frame
+    fn add_tick_event<E: Event>(&mut self) -> &mut Self;
+}
+
+impl TickApp for App {
+    fn add_tick_event<E: Event>(&mut self) -> &mut Self {
+        self
+            .init_resource::<Events<E>>()
+            .add_system_to_stage(
+                GameTick,
+                Events::<E>::update_system.label(BeginTick),
+            )
+    }
+}
+
+
+// Run criteria for the `GameTick` stage
+pub fn run_ticks(
+    time: Res<Time>,
+    state: Res<State<GameState>>,
+    session: Option<Res<NetSession>>,
+    mut clock: ResMut<GameClock>,
+    mut catching_up: Local<bool>,
+) -> ShouldRun {
+    // time is frozen while the game isn't being played
+    if *state.current() != GameState::Playing {
+        *catching_up = false;
+        return ShouldRun::No;
+    }
+
+    // only account for the frame time once per frame
+    if !*catching_up {
+        clock.lag = (clock.lag + time.delta()).min(MAX_LAG);
+    }
+
+    // over the network, the other end can't fall too far behind
+    let ready = session.is_none_or(|session| session.ready(clock.tick + 1));
+    if clock.rewound > 0 && ready {
+        clock.rewound -= 1;
+        clock.tick += 1;
+        *catching_up = true;
+        ShouldRun::YesAndCheckAgain
+    } else if clock.lag >= TICK && ready {
+        clock.lag -= TICK;
+        clock.tick += 1;
+        *catching_up = true;
+        ShouldRun::YesAndCheckAgain
+    } else {
+        *catching_up = false;
+        ShouldRun::No
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+
+    #[test]
+    fn rewound_ticks_are_run_again() {
+        let mut clock = GameClock { tick: 12, ..default() };
+        clock.rewind(9);
+        assert_eq!((clock.tick, clock.rewound), (9, 3));
+
+        // rolling back again before catching up still ends up where the
+        // clock was
+        clock.tick += 1;
+        clock.rewound -= 1;
+        clock.rewind(8);
+        assert_eq!((clock.tick, clock.rewound), (8, 4));
+    }
+}